@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rocket::tokio::runtime::{Builder, Runtime};
+use rocket_caching_layer::{Algorithm, CachedCompression};
+
+const SIZE: usize = 256 * 1024;
+const LEVELS: [u32; 3] = [1, 6, 9];
+const BUFFER_SIZES: [usize; 3] = [1024, 8 * 1024, 64 * 1024];
+
+/// Inputs that compress about as well as typical static files, from very well
+/// to barely.
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    let text = "The quick brown fox jumps over the lazy dog. ".repeat(SIZE / 45);
+    let json: String = (0..)
+        .map(|i| {
+            format!(
+                r#"{{"id":{i},"name":"item-{}","tags":["a","b"]}},"#,
+                i * 31 % 997
+            )
+        })
+        .take(SIZE / 48)
+        .collect();
+    // Close to incompressible, like images
+    let mut state = 0x2545_f491_u32;
+    let noise = (0..SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    vec![
+        ("text", text.into_bytes()),
+        ("json", json.into_bytes()),
+        ("noise", noise),
+    ]
+}
+
+struct Files(PathBuf);
+
+impl Files {
+    fn new() -> Self {
+        let name = format!("rocket-caching-layer-bench-{}", std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn write(&self, name: &str, contents: &[u8]) -> PathBuf {
+        let path = self.0.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for Files {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn compress(rt: &Runtime, algo: Algorithm, level: u32, buffer: usize, src: &Path, dst: &Path) {
+    rt.block_on(CachedCompression::compress_file(
+        algo, level, buffer, src, dst,
+    ))
+    .unwrap();
+}
+
+fn bench_compress(c: &mut Criterion) {
+    let rt = Builder::new_current_thread().enable_all().build().unwrap();
+    let files = Files::new();
+    for (name, contents) in inputs() {
+        let src = files.write(name, &contents);
+        let dst = files.0.join(format!("{name}.out"));
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(contents.len() as u64));
+        for algo in [Algorithm::Gzip, Algorithm::DeflateRaw] {
+            for level in LEVELS {
+                for buffer in BUFFER_SIZES {
+                    let id = BenchmarkId::new(format!("{algo}/{level}"), buffer);
+                    group.bench_function(id, |b| {
+                        b.iter(|| compress(&rt, algo, level, buffer, &src, &dst))
+                    });
+                }
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_compress);
+criterion_main!(benches);
@@ -0,0 +1,98 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rocket::{
+    fs::rewrite::{File, Rewrite, Rewriter},
+    http::Header,
+    local::blocking::Client,
+    tokio::runtime::Builder,
+};
+use rocket_caching_layer::{Algorithm, CachedCompression};
+
+/// Counts the allocations made on each thread, so the hit path's can be
+/// checked next to its timing.
+struct CountingAllocator;
+
+std::thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// How many allocations a hit may make beyond serving the file as-is: the
+/// copy's path, and the headers describing it.
+const HIT_ALLOCATIONS: usize = 12;
+
+/// How many hits are counted, so allocations made once, e.g. on the first
+/// one, don't count against each.
+const REPEATS: usize = 100;
+
+/// How many allocations `f` makes on this thread.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+fn bench_hits(c: &mut Criterion) {
+    let rt = Builder::new_current_thread().enable_all().build().unwrap();
+    let name = format!("rocket-caching-layer-hits-{}", std::process::id());
+    let dir = std::env::temp_dir().join(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("index.txt");
+    std::fs::write(&path, "Hello world! ".repeat(100)).unwrap();
+    let cache = CachedCompression::new();
+    assert!(rt.block_on(cache.warm_file(path.clone(), Algorithm::Gzip)));
+
+    let client = Client::untracked(rocket::build()).unwrap();
+    let mut group = c.benchmark_group("rewrite");
+    // Negotiated the same way but served as-is, a baseline for what a hit adds
+    let mut per_request = vec![];
+    for accept in ["identity", "gzip"] {
+        let req = client
+            .get("/index.txt")
+            .header(Header::new("Accept-Encoding", accept));
+        let rewrite = || {
+            let file = Some(Rewrite::File(File::new(path.as_path())));
+            cache.rewrite(file, req.inner())
+        };
+        // Rewriting is synchronous, so everything it allocates is counted
+        let count = allocations(|| (0..REPEATS).for_each(|_| drop(rewrite()))) / REPEATS;
+        println!("rewrite/{accept}: {count} allocations");
+        per_request.push(count);
+        group.bench_function(accept, |b| b.iter(&rewrite));
+    }
+    group.finish();
+    let [identity, hit] = per_request[..] else {
+        unreachable!()
+    };
+    assert!(
+        hit <= identity + HIT_ALLOCATIONS,
+        "a repeated hit makes {hit} allocations, at most {} were expected",
+        identity + HIT_ALLOCATIONS
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, bench_hits);
+criterion_main!(benches);
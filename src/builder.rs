@@ -0,0 +1,1122 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use rocket::{figment::Figment, http::ContentType, serde::Deserialize, trace::warn};
+
+use crate::{
+    filter::{Filters, Pattern},
+    manifest::{file_policy, ManifestFile, Overrides},
+    parse_preference,
+    window::Window,
+    Algorithm, ArtifactStore, CachedCompression, ColdMissPolicy, Error, ErrorHook, FileSystem,
+    LevelBySize, PathPredicate, PostProcess, RequestPredicate, TARGET,
+};
+
+/// A source of the current time.
+pub(crate) type Clock = Arc<dyn Fn() -> SystemTime + Send + Sync>;
+
+/// Options shared by a [`CachedCompression`] and its background tasks.
+#[derive(Default)]
+pub(crate) struct Config {
+    pub(crate) track_inode: bool,
+    pub(crate) artifact_mode: Option<u32>,
+    pub(crate) bypass: Option<RequestPredicate>,
+    pub(crate) serve_compressed_if: Option<RequestPredicate>,
+    pub(crate) eligible: Option<PathPredicate>,
+    pub(crate) mount_prefixes: Vec<String>,
+    pub(crate) tier: Option<(u32, u32)>,
+    pub(crate) level_by_size: Option<LevelBySize>,
+    pub(crate) stale_while_revalidate: bool,
+    pub(crate) min_age: Option<Duration>,
+    pub(crate) on_cache_error: Option<ErrorHook>,
+    pub(crate) gzip_embed_metadata: bool,
+    pub(crate) temp_dir: Option<PathBuf>,
+    pub(crate) first_hit_wait: Option<Duration>,
+    pub(crate) retry: Option<(u32, Duration)>,
+    pub(crate) parallel: Option<usize>,
+    pub(crate) store: Option<Box<dyn ArtifactStore>>,
+    pub(crate) include: Vec<String>,
+    pub(crate) exclude: Vec<String>,
+    pub(crate) also_vary_on: Vec<String>,
+    pub(crate) vary_only_if_compressible: bool,
+    pub(crate) debug_headers: bool,
+    pub(crate) adaptive: bool,
+    pub(crate) min_savings_ratio: Option<f64>,
+    pub(crate) write_metadata: bool,
+    pub(crate) preference: Vec<Algorithm>,
+    pub(crate) window: Option<Window>,
+    pub(crate) manifest: Option<ManifestFile>,
+    pub(crate) max_pending: Option<usize>,
+    pub(crate) min_requests: Option<u64>,
+    pub(crate) size_based: Option<(u64, Algorithm, Algorithm)>,
+    pub(crate) progressive_flush: Option<usize>,
+    pub(crate) low_priority: bool,
+    pub(crate) cold_miss: ColdMissPolicy,
+    pub(crate) append_mode: bool,
+    pub(crate) revalidate: Option<Duration>,
+    pub(crate) serve_only: bool,
+    pub(crate) file_policies: bool,
+    pub(crate) post_process: Option<PostProcess>,
+    /// Derived from the other options by `build()`.
+    pub(crate) offered: Offered,
+    pub(crate) filters: Filters,
+    pub(crate) fallback: Vec<Algorithm>,
+    pub(crate) cache_version: Option<String>,
+    pub(crate) source_root: Option<PathBuf>,
+    /// Replaces the system clock, so tests can move through the window.
+    pub(crate) clock: Option<Clock>,
+}
+
+/// The offered algorithms, and where each ranks in the preference, indexed
+/// by `Algorithm as usize`, so negotiating doesn't search for them on each
+/// request.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Offered {
+    pub(crate) enabled: [bool; Algorithm::ALL.len()],
+    pub(crate) rank: [Option<usize>; Algorithm::ALL.len()],
+}
+
+impl Offered {
+    fn of(config: &Config) -> Self {
+        let mut offered = Self::default();
+        for algo in config.algorithms() {
+            offered.enabled[*algo as usize] = true;
+        }
+        for (i, algo) in config.preference.iter().enumerate() {
+            offered.rank[*algo as usize] = Some(i);
+        }
+        offered
+    }
+}
+
+impl Config {
+    /// The algorithms offered to clients, most preferred first.
+    pub(crate) fn algorithms(&self) -> &[Algorithm] {
+        if self.preference.is_empty() {
+            &Algorithm::ALL
+        } else {
+            &self.preference
+        }
+    }
+
+    /// The algorithm to try after compressing with `algo` failed, if any.
+    pub(crate) fn fallback_after(&self, algo: Algorithm) -> Option<Algorithm> {
+        let i = self.fallback.iter().position(|a| *a == algo)?;
+        self.fallback.get(i + 1).copied()
+    }
+
+    /// Whether `path` is under the `source_root()`, if one is set. Paths
+    /// stepping out of a directory with `..` never are.
+    pub(crate) fn within_root(&self, path: &Path) -> bool {
+        self.source_root.as_ref().is_none_or(|root| {
+            let up = path.components().any(|c| c == Component::ParentDir);
+            !up && path.starts_with(root)
+        })
+    }
+
+    /// Whether the request path `path` is under one of the `compress_under()`
+    /// prefixes, if any are set.
+    pub(crate) fn under_mount(&self, path: &str) -> bool {
+        let under = |prefix: &String| {
+            let prefix = prefix.trim_end_matches('/');
+            path.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        self.mount_prefixes.is_empty() || self.mount_prefixes.iter().any(under)
+    }
+
+    /// The overrides in the policy of the file at `path`, with
+    /// `file_policies()`.
+    pub(crate) fn policy(&self, path: &Path) -> Overrides {
+        match self.file_policies.then(|| file_policy(path)) {
+            Some(Ok(policy)) => policy,
+            Some(Err(e)) => {
+                warn!(target: TARGET, %e, "Ignoring the compression policy");
+                Overrides::default()
+            }
+            None => Overrides::default(),
+        }
+    }
+
+    /// The algorithm and level overrides for the file at `path`, from its own
+    /// `policy`, already read, then from the manifest.
+    pub(crate) fn overrides_with(&self, path: &Path, policy: Overrides) -> Overrides {
+        let manifest = self
+            .manifest
+            .as_ref()
+            .map(|m| m.overrides(path))
+            .unwrap_or_default();
+        Overrides {
+            algorithm: policy.algorithm.or(manifest.algorithm),
+            level: policy.level.or(manifest.level),
+        }
+    }
+
+    /// The `cache_version()` copies are stored under, if any.
+    pub(crate) fn version(&self) -> Option<&str> {
+        self.cache_version.as_deref()
+    }
+
+    /// The current time, as far as the compression window is concerned.
+    pub(crate) fn now(&self) -> SystemTime {
+        self.clock
+            .as_ref()
+            .map_or_else(SystemTime::now, |clock| clock())
+    }
+
+    /// Where compressed copies are kept.
+    pub(crate) fn store(&self) -> &dyn ArtifactStore {
+        self.store.as_deref().unwrap_or(&FileSystem)
+    }
+
+    /// Whether the file at `path` is selected for compression by the
+    /// `include` and `exclude` patterns, as parsed on build.
+    pub(crate) fn compressible(&self, path: &Path) -> bool {
+        self.filters.selects(path)
+    }
+
+    /// Whether bodies of the type `ct` are selected for compression, like
+    /// files with the type's usual extension.
+    pub(crate) fn compressible_type(&self, ct: &ContentType) -> bool {
+        self.filters.selects_type(ct)
+    }
+
+    /// Checks that the options make sense together, returning a description
+    /// of the first problem found.
+    fn validate(&self) -> Result<(), String> {
+        if let Some((initial, final_level)) = self.tier {
+            if initial > 9 || final_level > 9 {
+                return Err("recompress_tier() levels must be between 0 and 9".into());
+            }
+            if initial >= final_level {
+                return Err(
+                    "recompress_tier() initial level must be lower than the final level".into(),
+                );
+            }
+        }
+        if self.artifact_mode.is_some_and(|mode| mode > 0o7777) {
+            return Err("artifact_mode() must only contain permission bits".into());
+        }
+        if self.retry.is_some_and(|(attempts, _)| attempts == 0) {
+            return Err("retry() needs at least one attempt".into());
+        }
+        if self
+            .min_savings_ratio
+            .is_some_and(|ratio| !(0. ..=1.).contains(&ratio))
+        {
+            return Err("min_savings_ratio() must be between 0 and 1".into());
+        }
+        for (i, algo) in self.preference.iter().enumerate() {
+            if self.preference[..i].contains(algo) {
+                return Err(format!("prefer() lists `{algo}` more than once"));
+            }
+        }
+        for (i, algo) in self.fallback.iter().enumerate() {
+            if self.fallback[..i].contains(algo) {
+                return Err(format!("fallback() lists `{algo}` more than once"));
+            }
+        }
+        let is_version = |v: &String| !v.is_empty() && !v.contains(['.', '/', '\\']);
+        if self.cache_version.as_ref().is_some_and(|v| !is_version(v)) {
+            return Err("cache_version() needs a non-empty name without dots or slashes".into());
+        }
+        if let Some(window) = &self.window {
+            window.validate()?;
+        }
+        if let Some(manifest) = &self.manifest {
+            manifest.refresh()?;
+        }
+        if self.revalidate.is_some_and(|every| every.is_zero()) {
+            return Err("in_memory_hits() needs a positive interval".into());
+        }
+        if self.progressive_flush == Some(0) {
+            return Err("progressive_flush() needs a positive interval".into());
+        }
+        if self.max_pending == Some(0) {
+            return Err("max_pending() needs room for at least one compression".into());
+        }
+        if self.parallel == Some(0) {
+            return Err("parallel() needs at least one thread".into());
+        }
+        if !self
+            .mount_prefixes
+            .iter()
+            .all(|prefix| prefix.starts_with('/'))
+        {
+            return Err("compress_under() needs absolute paths, starting with `/`".into());
+        }
+        for pattern in self.include.iter().chain(&self.exclude) {
+            Pattern::parse(pattern)?;
+        }
+        let is_name = |name: &String| {
+            !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b',')
+        };
+        if !self.also_vary_on.iter().all(is_name) {
+            return Err("also_vary_on() needs header names".into());
+        }
+        if self.stale_while_revalidate && !self.track_inode {
+            return Err("stale_while_revalidate() requires track_inode()".into());
+        }
+        Ok(())
+    }
+}
+
+/// The options that can be set from a figment, under its `compression` key.
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde", default)]
+struct FilterOptions {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    preference: Option<String>,
+}
+
+/// Builder for a [`CachedCompression`], created by
+/// [`CachedCompression::builder()`].
+#[derive(Default)]
+pub struct CachedCompressionBuilder {
+    config: Config,
+}
+
+impl CachedCompressionBuilder {
+    /// Treat cached copies as stale when the device or inode of the source file
+    /// changes, even if the path stays the same. This catches deploys that swap
+    /// the served directory atomically.
+    ///
+    /// Only has an effect on Unix.
+    pub fn track_inode(mut self) -> Self {
+        self.config.track_inode = true;
+        self
+    }
+
+    /// Set the permission bits of generated compressed files, e.g. `0o644`.
+    /// By default, they are created with the process umask.
+    ///
+    /// Only has an effect on Unix.
+    pub fn artifact_mode(mut self, mode: u32) -> Self {
+        self.config.artifact_mode = Some(mode);
+        self
+    }
+
+    /// Serve the original file, without dispatching any compression, to
+    /// requests matching `f`. For example, to send uncompressed content to
+    /// clients with a `debug=1` cookie:
+    ///
+    /// ```rust,no_run
+    /// # use rocket_caching_layer::CachedCompression;
+    /// CachedCompression::builder()
+    ///     .bypass_when(|req| req.cookies().get("debug").is_some_and(|c| c.value() == "1"))
+    ///     .build();
+    /// ```
+    pub fn bypass_when<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&rocket::Request<'_>) -> bool + Send + Sync + 'static,
+    {
+        self.config.bypass = Some(Box::new(f));
+        self
+    }
+
+    /// Only compress files requested at paths matching `f`, which is given the
+    /// path of the request as sent, e.g. `/assets/app.js`. This lets one
+    /// instance, sharing its cache, be added to several `FileServer`s with
+    /// different policies:
+    ///
+    /// ```rust,no_run
+    /// # use rocket::fs::FileServer;
+    /// # use rocket_caching_layer::CachedCompression;
+    /// let cache = CachedCompression::builder()
+    ///     .eligible_when(|path| !path.starts_with("/downloads/"))
+    ///     .build();
+    /// rocket::build()
+    ///     .mount("/", FileServer::new("static").rewrite(cache.clone()))
+    ///     .mount("/downloads", FileServer::new("downloads").rewrite(cache));
+    /// ```
+    ///
+    /// Other files are served as-is, like with [`bypass_when`](Self::bypass_when).
+    ///
+    /// By default, files at every path are eligible.
+    pub fn eligible_when<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.config.eligible = Some(Box::new(f));
+        self
+    }
+
+    /// Only compress files requested under the mount point `prefix`, e.g.
+    /// `/assets`, which matches `/assets/app.js` but not `/assets-old/app.js`.
+    /// Can be given several times, allowing each. This keeps a shared
+    /// instance, like the [`global`](CachedCompression::global) one, from
+    /// compressing what other mounts serve:
+    ///
+    /// ```rust,no_run
+    /// # use rocket::fs::FileServer;
+    /// # use rocket_caching_layer::CachedCompression;
+    /// let cache = CachedCompression::builder().compress_under("/assets").build();
+    /// rocket::build()
+    ///     .mount("/assets", FileServer::new("assets").rewrite(cache.clone()))
+    ///     .mount("/downloads", FileServer::new("downloads").rewrite(cache));
+    /// ```
+    ///
+    /// Applies along with [`eligible_when`](Self::eligible_when).
+    ///
+    /// By default, files under every mount point are compressed.
+    pub fn compress_under(mut self, prefix: impl Into<String>) -> Self {
+        self.config.mount_prefixes.push(prefix.into());
+        self
+    }
+
+    /// Only serve compressed copies to requests matching `f`, e.g. to send the
+    /// original file to legacy user agents that mishandle `Content-Encoding`.
+    /// Unlike [`bypass_when`](Self::bypass_when), other requests still warm the
+    /// cache for clients that do match.
+    ///
+    /// If `f` looks at headers other than `Accept-Encoding`, consider adding
+    /// them to the `Vary` header of the response.
+    pub fn serve_compressed_if<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&rocket::Request<'_>) -> bool + Send + Sync + 'static,
+    {
+        self.config.serve_compressed_if = Some(Box::new(f));
+        self
+    }
+
+    /// Compress files at `initial` level when first requested, so a compressed
+    /// copy is available quickly, then recompress them at `final_level` in the
+    /// background, one at a time, once no other compression is pending (and
+    /// the [`compress_window`](Self::compress_window) is open, if set). The
+    /// quick copy is served until the better one replaces it.
+    ///
+    /// Levels range from 0 to 9, and default to 9 without a tier.
+    pub fn recompress_tier(mut self, initial: u32, final_level: u32) -> Self {
+        self.config.tier = Some((initial, final_level));
+        self
+    }
+
+    /// Compress each file at the level `f` picks for its size in bytes, e.g.
+    /// to compress small files quickly and large ones hard:
+    ///
+    /// ```rust
+    /// # use rocket_caching_layer::CachedCompression;
+    /// CachedCompression::builder()
+    ///     .level_by_size(|size| if size < 64 * 1024 { 4 } else { 9 })
+    ///     .build();
+    /// ```
+    ///
+    /// Levels above 9 are treated as 9. The level picked replaces any
+    /// [`recompress_tier`](Self::recompress_tier), and a level set for the
+    /// file by the [`manifest`](Self::manifest) replaces it.
+    ///
+    /// By default, every file is compressed at level 9.
+    pub fn level_by_size<F>(mut self, f: F) -> Self
+    where
+        F: Fn(u64) -> u32 + Send + Sync + 'static,
+    {
+        self.config.level_by_size = Some(Box::new(f));
+        self
+    }
+
+    /// Keep serving a compressed copy that has gone stale, e.g. because
+    /// [`track_inode`](Self::track_inode) noticed the source was replaced,
+    /// while a fresh copy is generated. The stale copy is replaced atomically
+    /// once the new one is complete.
+    ///
+    /// By default, the original file is served until the fresh copy is ready.
+    pub fn stale_while_revalidate(mut self) -> Self {
+        self.config.stale_while_revalidate = true;
+        self
+    }
+
+    /// Don't compress files modified less than `age` ago, since they may still
+    /// be being written, e.g. by an upload. Requests are served the original
+    /// file until it has settled, and the next request after that compresses it.
+    pub fn min_age(mut self, age: Duration) -> Self {
+        self.config.min_age = Some(age);
+        self
+    }
+
+    /// Call `f` with the path of a cached copy, and the error, when a copy that
+    /// should be served turns out to be missing or unusable, e.g. because it
+    /// was deleted from disk. The original file is served instead, and a new
+    /// copy is generated.
+    ///
+    /// By default, these are logged as warnings.
+    pub fn on_cache_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Path, &io::Error) + Send + Sync + 'static,
+    {
+        self.config.on_cache_error = Some(Box::new(f));
+        self
+    }
+
+    /// Record the original file name and modification time in the header of
+    /// gzip copies, so `gunzip -N` restores them.
+    ///
+    /// By default, gzip copies carry no metadata.
+    pub fn gzip_embed_metadata(mut self) -> Self {
+        self.config.gzip_embed_metadata = true;
+        self
+    }
+
+    /// Write compressed copies into `dir` first, and only move them next to
+    /// the source once complete. Useful when the served directory is on a small
+    /// partition. If `dir` is on a different filesystem, the finished copy is
+    /// copied over instead.
+    ///
+    /// By default, copies are written next to the source, under a temporary name.
+    pub fn temp_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.temp_dir = Some(dir.into());
+        self
+    }
+
+    /// Rather than serving the original file on the first request for it, wait
+    /// up to `wait` for the compressed copy to be generated, and serve that if
+    /// it's ready in time.
+    ///
+    /// This blocks the request's worker thread while waiting, using tokio's
+    /// `block_in_place`, so it only takes effect on a multi-threaded runtime
+    /// (which Rocket uses by default). Keep `wait` short.
+    pub fn warm_on_first_hit_blocking(mut self, wait: Duration) -> Self {
+        self.config.first_hit_wait = Some(wait);
+        self
+    }
+
+    /// Make up to `attempts` tries at compressing a file when it fails with a
+    /// transient error, like running out of file descriptors, waiting
+    /// `backoff` before the first retry and twice as long before each one
+    /// after. Other errors, like a missing file, aren't retried.
+    ///
+    /// Defaults to 3 attempts, with a 50ms backoff. Use 1 attempt to disable
+    /// retries.
+    pub fn retry(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.config.retry = Some((attempts, backoff));
+        self
+    }
+
+    /// Compress gzip copies on up to `threads` threads, by splitting the file
+    /// into blocks and compressing each into a gzip member of its own. Gzip
+    /// allows a stream of several members, which `gunzip` and browsers decode
+    /// as the concatenation of their contents, at the cost of slightly larger
+    /// output. Copies using other algorithms are unaffected.
+    ///
+    /// The compression runs on tokio's blocking thread pool.
+    ///
+    /// By default, each file is compressed as a single stream, on one thread.
+    pub fn parallel(mut self, threads: usize) -> Self {
+        self.config.parallel = Some(threads);
+        self
+    }
+
+    /// Keep compressed copies in `store`, e.g. to share them between several
+    /// servers.
+    ///
+    /// By default, they are stored on the local filesystem, next to the source
+    /// files. See [`FileSystem`].
+    pub fn store(mut self, store: impl ArtifactStore + 'static) -> Self {
+        self.config.store = Some(Box::new(store));
+        self
+    }
+
+    /// Only compress files matching `pattern`. Patterns are either an
+    /// extension, like `js`, or a media type, like `application/json` or
+    /// `text/*`. Once any are included, files matching none of them are served
+    /// as-is.
+    ///
+    /// Types with a `+xml` or `+json` suffix, like `image/svg+xml`, are text,
+    /// so they're matched by `text/*` as well as by their own top-level type,
+    /// e.g. `image/*`. Name them in full, or by extension, to match them alone.
+    ///
+    /// By default, every file is compressed, except media formats that are
+    /// compressed already, like PNG images or WOFF2 fonts.
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.config.include.push(pattern.into());
+        self
+    }
+
+    /// Never compress files matching `pattern`, even if they are
+    /// [included](Self::include). Accepts the same patterns.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.config.exclude.push(pattern.into());
+        self
+    }
+
+    /// Read options from the `compression` key of `figment`, e.g. Rocket's
+    /// configuration, so they can be changed in `Rocket.toml`:
+    ///
+    /// ```toml
+    /// [default.compression]
+    /// include = ["text/*", "js", "application/json"]
+    /// exclude = ["text/csv"]
+    /// preference = "deflate-raw, gzip"
+    /// ```
+    ///
+    /// ```rust,no_run
+    /// # use rocket_caching_layer::CachedCompression;
+    /// # let rocket = rocket::build();
+    /// CachedCompression::builder().configure(rocket.figment())?.try_build()?;
+    /// # Ok::<(), rocket_caching_layer::Error>(())
+    /// ```
+    ///
+    /// Patterns are added to any set on the builder, and are validated by
+    /// [`build`](Self::build). A `preference` replaces any set with
+    /// [`prefer`](Self::prefer), and fails here with [`Error::Invalid`] if it
+    /// is invalid, see [`parse_preference`]. Options that can't be read fail
+    /// with [`Error::Config`].
+    pub fn configure(mut self, figment: &Figment) -> Result<Self, Error> {
+        let filters: FilterOptions = figment.focus("compression").extract()?;
+        self.config.include.extend(filters.include);
+        self.config.exclude.extend(filters.exclude);
+        if let Some(preference) = filters.preference {
+            self.config.preference = parse_preference(&preference).map_err(Error::Invalid)?;
+        }
+        Ok(self)
+    }
+
+    /// Only offer the algorithms in `order`, and when a client accepts several
+    /// of them equally, pick the earliest. A preference can also be read from
+    /// a string, like `"gzip, deflate-raw"`, with [`parse_preference`].
+    ///
+    /// By default, every algorithm is offered, and the client's order breaks
+    /// ties.
+    pub fn prefer(mut self, order: &[Algorithm]) -> Self {
+        self.config.preference = order.to_vec();
+        self
+    }
+
+    /// Add `headers` to the `Vary` header sent with files, alongside
+    /// `Accept-Encoding`. Useful when an earlier rewriter picks the file based
+    /// on them, e.g. on `Accept-Language` for localized pages, so caches keep
+    /// the variants apart.
+    pub fn also_vary_on(mut self, headers: &[&str]) -> Self {
+        self.config
+            .also_vary_on
+            .extend(headers.iter().map(|h| h.to_string()));
+        self
+    }
+
+    /// Only add `Accept-Encoding` to the `Vary` header of files selected for
+    /// compression by [`include`](Self::include) and
+    /// [`exclude`](Self::exclude), or by default, whether or not a copy is
+    /// cached yet. Files that are never compressed, e.g. PNG images, are the
+    /// same for every client, so downstream caches can keep a single copy of
+    /// them. Headers from
+    /// [`also_vary_on`](Self::also_vary_on) are still added.
+    ///
+    /// By default, `Accept-Encoding` is added to every file, except archives.
+    pub fn vary_only_if_compressible(mut self) -> Self {
+        self.config.vary_only_if_compressible = true;
+        self
+    }
+
+    /// Add an `X-Compression-Ratio` header to compressed files, with the size
+    /// of the copy relative to the original, e.g. `0.312`, to check how well
+    /// compression works from the browser, and an `X-Cache-Age` header, with
+    /// how many seconds ago the copy was generated, to check it's replaced
+    /// when the original changes.
+    ///
+    /// By default, no debugging headers are sent.
+    pub fn debug_headers(mut self) -> Self {
+        self.config.debug_headers = true;
+        self
+    }
+
+    /// Learn how well each algorithm compresses files of each extension, and
+    /// when a client accepts several equally, compress and serve files with
+    /// the one that has done best on that type so far, rather than the first
+    /// listed. Algorithms that haven't compressed a few files of the type yet
+    /// are tried first, in the client's order. Encodings the client weighs
+    /// higher always come first.
+    ///
+    /// By default, the client's order is always used.
+    pub fn adaptive(mut self) -> Self {
+        self.config.adaptive = true;
+        self
+    }
+
+    /// Serve files as-is when compressing them saves less than `ratio` of
+    /// their size, e.g. `0.1` for 10%, since decompressing them would cost
+    /// clients more than the bandwidth saved. The compressed copy is deleted,
+    /// and the file isn't compressed with that algorithm again unless it's
+    /// replaced (as noticed by [`track_inode`](Self::track_inode)). Any other
+    /// algorithm the client accepts is still tried.
+    ///
+    /// By default, any compressed copy is served.
+    pub fn min_savings_ratio(mut self, ratio: f64) -> Self {
+        self.config.min_savings_ratio = Some(ratio);
+        self
+    }
+
+    /// Write a JSON file describing each compressed copy next to it, named
+    /// after the copy with `.meta` appended (e.g. `index.txt.gzip.meta`), for
+    /// external tooling. It records the algorithm, compression level, sizes of
+    /// the source and the copy, the source's modification time in seconds
+    /// since the Unix epoch, and an ETag for the copy:
+    ///
+    /// ```json
+    /// {"algorithm":"gzip","level":9,"original_size":1024,"compressed_size":312,
+    ///  "source_mtime":1700000000,"etag":"\"6553f100-400-gzip\""}
+    /// ```
+    ///
+    /// The file is written through the [`store`](Self::store), after the copy.
+    ///
+    /// By default, no metadata is written.
+    pub fn write_metadata(mut self) -> Self {
+        self.config.write_metadata = true;
+        self
+    }
+
+    /// Read per-file overrides from the `compression.toml` manifest in `root`,
+    /// the directory the `FileServer` serves. Each `[[override]]` selects files
+    /// with the same patterns as [`include`](Self::include), and sets the only
+    /// `algorithm` to compress them with, their compression `level`, or both:
+    ///
+    /// ```toml
+    /// [[override]]
+    /// files = "wasm"
+    /// algorithm = "gzip"
+    /// level = 6
+    ///
+    /// [[override]]
+    /// files = "text/*"
+    /// level = 9
+    /// ```
+    ///
+    /// The first matching entry that sets each wins, and anything not set
+    /// falls back to the other options. A level set here replaces any
+    /// [`recompress_tier`](Self::recompress_tier).
+    ///
+    /// The manifest is read by [`build`](Self::build), which panics if it is
+    /// invalid, and read again before compressing a file whenever it has
+    /// changed. If it turns invalid, the previous version is kept. A missing
+    /// manifest overrides nothing.
+    ///
+    /// By default, no manifest is read.
+    pub fn manifest(mut self, root: impl AsRef<Path>) -> Self {
+        self.config.manifest = Some(ManifestFile::new(root.as_ref()));
+        self
+    }
+
+    /// Limit how many compressions can be running or queued at once, e.g. when
+    /// many files are requested for the first time together. Files that would
+    /// need another are served as-is without scheduling one, until the
+    /// backlog drains and a later request for them does.
+    ///
+    /// By default, there is no limit.
+    pub fn max_pending(mut self, limit: usize) -> Self {
+        self.config.max_pending = Some(limit);
+        self
+    }
+
+    /// Only compress a file once it was requested `count` times without a
+    /// copy to serve, so files requested once in a while aren't worth the
+    /// work. Until then, it's served as-is. Requests are counted per file,
+    /// from the clients accepting a supported encoding.
+    ///
+    /// By default, a file is compressed on its first request.
+    pub fn min_requests(mut self, count: u64) -> Self {
+        self.config.min_requests = Some(count);
+        self
+    }
+
+    /// Prefer `small` for files smaller than `threshold` bytes, and `large` for
+    /// the rest, whenever the client accepts it as much as any other encoding,
+    /// e.g. to save a slower but stronger algorithm for files where it pays
+    /// off. Otherwise, the usual order applies.
+    ///
+    /// By default, the size of a file doesn't affect the algorithm.
+    pub fn size_based_algorithm(
+        mut self,
+        threshold: u64,
+        small: Algorithm,
+        large: Algorithm,
+    ) -> Self {
+        self.config.size_based = Some((threshold, small, large));
+        self
+    }
+
+    /// Flush the compressed stream after about every `bytes` of input, so
+    /// clients can decompress what they have received so far, before the rest
+    /// of the file arrives. Each flush ends on a byte boundary, at the cost of
+    /// a few bytes and slightly worse compression.
+    ///
+    /// By default, the stream is only flushed at the end.
+    pub fn progressive_flush(mut self, bytes: usize) -> Self {
+        self.config.progressive_flush = Some(bytes);
+        self
+    }
+
+    /// When compressing a file with one of the algorithms in `chain` fails,
+    /// after any [`retry`](Self::retry), compress it with the next one
+    /// instead. Once a later algorithm succeeds, the ones that failed are
+    /// skipped for that file, and clients accepting the replacement are served
+    /// it, until the file is replaced (as noticed by
+    /// [`track_inode`](Self::track_inode)).
+    ///
+    /// By default, a failed compression is only attempted again on a later
+    /// request.
+    pub fn fallback(mut self, chain: &[Algorithm]) -> Self {
+        self.config.fallback = chain.to_vec();
+        self
+    }
+
+    /// Store compressed copies under `version`, which becomes part of their
+    /// names, e.g. `index.html.v2.gzip`. Bumping it on deploy ignores every
+    /// copy generated before, so none can be served stale, and
+    /// [`prune_orphans`](CachedCompression::prune_orphans) removes them.
+    ///
+    /// By default, copies are named after the file and the algorithm alone,
+    /// e.g. `index.html.gzip`.
+    pub fn cache_version(mut self, version: impl Into<String>) -> Self {
+        self.config.cache_version = Some(version.into());
+        self
+    }
+
+    /// Only compress files under `root`, the directory the `FileServer`
+    /// serves, as a guard against paths from misordered rewriters. Anything
+    /// else is logged and served as-is, without reading it or writing a copy
+    /// next to it. Paths are compared as given, so `root` must be spelled the
+    /// same way as for the `FileServer`, e.g. `static` for
+    /// `FileServer::new("static")`.
+    ///
+    /// By default, any path from the rewrite chain is compressed.
+    pub fn source_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.config.source_root = Some(root.into());
+        self
+    }
+
+    /// Let other tasks, like request handlers, run regularly while compressing
+    /// a file, rather than only when reading or writing it has to wait, so a
+    /// large file doesn't hold up a runtime thread on a busy server.
+    /// Compressing takes a little longer in exchange. Doesn't apply to
+    /// [`parallel`](Self::parallel) compression, which runs on threads of its
+    /// own.
+    ///
+    /// By default, compression only yields when it waits on the file system.
+    pub fn low_priority(mut self) -> Self {
+        self.config.low_priority = true;
+        self
+    }
+
+    /// How to respond to the first requests for a file, before its compressed
+    /// copy is ready. With [`ColdMissPolicy::RetryAfter`], clients are asked
+    /// to come back once it should be, rather than being sent the original,
+    /// e.g. to keep large uncompressed responses off a latency budget:
+    ///
+    /// ```rust,no_run
+    /// # use std::time::Duration;
+    /// # use rocket::fs::FileServer;
+    /// # use rocket_caching_layer::{CachedCompression, ColdMissPolicy};
+    /// let cache = CachedCompression::builder()
+    ///     .cold_miss_policy(ColdMissPolicy::RetryAfter(Duration::from_secs(2)))
+    ///     .build();
+    /// rocket::build()
+    ///     .attach(cache.clone())
+    ///     .mount("/", FileServer::new("static").rewrite(cache));
+    /// ```
+    ///
+    /// The `503` is set by the fairing, so the instance must also be attached.
+    /// Clients that don't accept a supported encoding are always sent the
+    /// original.
+    ///
+    /// By default, the original is served.
+    pub fn cold_miss_policy(mut self, policy: ColdMissPolicy) -> Self {
+        self.config.cold_miss = policy;
+        self
+    }
+
+    /// Treat files as append-only, like logs: when one grows, only the new
+    /// bytes are compressed, into a gzip member appended to the cached copy,
+    /// which decodes to the whole file since decoders concatenate members.
+    /// Each request checks the size of the file, so a copy is stale once it
+    /// grew, with the same `stat` as [`track_inode`](Self::track_inode) if
+    /// set, or in the background with [`in_memory_hits`](Self::in_memory_hits).
+    /// Other encodings, and files that shrank or were replaced, are
+    /// compressed again in full.
+    ///
+    /// By default, a file that changed is always compressed again in full.
+    pub fn append_mode(mut self) -> Self {
+        self.config.append_mode = true;
+        self
+    }
+
+    /// Serve cached copies without touching the file system, so requests
+    /// never wait on it: once a copy is generated, where the store serves it
+    /// from is kept in memory, and changes to the source noticed by
+    /// [`track_inode`](Self::track_inode) or [`append_mode`](Self::append_mode)
+    /// are looked for by a background task, every `every`, rather than on
+    /// each request. The same task checks the copies are still usable,
+    /// reporting those that aren't to
+    /// [`on_cache_error`](Self::on_cache_error).
+    ///
+    /// The size of the source, for
+    /// [`size_based_algorithm`](Self::size_based_algorithm), and its policy,
+    /// for [`file_policies`](Self::file_policies), are kept from the same
+    /// check. A source replaced, a copy deleted, or a policy changed is only
+    /// noticed by the next check, and until then requests get what was
+    /// cached.
+    ///
+    /// By default, each request checks the copy it's served, and the source
+    /// if needed.
+    pub fn in_memory_hits(mut self, every: Duration) -> Self {
+        self.config.revalidate = Some(every);
+        self
+    }
+
+    /// Never compress files in response to requests, only serve copies
+    /// generated ahead of time, e.g. at build time, stored at their artifact
+    /// paths. A copy older than its source is ignored, and files without one
+    /// are served as-is. [`warm`](CachedCompression::warm) and the like still
+    /// compress files when called.
+    ///
+    /// By default, requests for files without a copy dispatch a compression.
+    pub fn serve_only(mut self) -> Self {
+        self.config.serve_only = true;
+        self
+    }
+
+    /// Read a policy for each file, pinning the algorithm and level to
+    /// compress it with, like a [`manifest`](Self::manifest) entry for that
+    /// file alone, which it takes precedence over. The policy is read from
+    /// the file's `user.compression` extended attribute on Linux, or
+    /// otherwise from a `.policy` file next to it, e.g. `app.wasm.policy`:
+    ///
+    /// ```toml
+    /// algorithm = "gzip"
+    /// level = 6
+    /// ```
+    ///
+    /// On Linux, it can be set with
+    /// `setfattr -n user.compression -v 'algorithm = "gzip"' app.wasm`.
+    ///
+    /// A file's policy is read once, and again once the file changes, its
+    /// attributes included, but not when only its `.policy` file does. An
+    /// invalid one is logged, and overrides nothing.
+    ///
+    /// By default, no policies are read.
+    pub fn file_policies(mut self) -> Self {
+        self.config.file_policies = true;
+        self
+    }
+
+    /// Pass every compressed copy through `f` as it's written, before it's
+    /// stored, as a series of chunks of any size, in order. `f` may change each chunk in place,
+    /// e.g. to checksum or sign the copy, and whatever it leaves is written.
+    /// Leaving the chunks as they are keeps the copy a valid stream:
+    ///
+    /// ```rust
+    /// # use rocket_caching_layer::CachedCompression;
+    /// CachedCompression::builder()
+    ///     .post_process(|chunk| println!("writing {} bytes", chunk.len()))
+    ///     .build();
+    /// ```
+    ///
+    /// With [`append_mode`](Self::append_mode), only the appended bytes are
+    /// passed through `f`.
+    ///
+    /// By default, copies are stored as compressed.
+    pub fn post_process<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut Vec<u8>) + Send + Sync + 'static,
+    {
+        self.config.post_process = Some(Box::new(f));
+        self
+    }
+
+    /// Only compress files during a daily off-peak window, from `start` until
+    /// `end`, given as offsets from midnight UTC. The window wraps around
+    /// midnight if `end` is before `start`, so 22:00 to 06:00 is:
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # use rocket_caching_layer::CachedCompression;
+    /// let hours = |h| Duration::from_secs(h * 60 * 60);
+    /// CachedCompression::builder().compress_window(hours(22), hours(6)).build();
+    /// ```
+    ///
+    /// Files requested outside the window are served as-is, and queued to be
+    /// compressed in a batch once it opens, by a background task.
+    ///
+    /// By default, files are compressed as soon as they are requested.
+    pub fn compress_window(mut self, start: Duration, end: Duration) -> Self {
+        self.config.window = Some(Window { start, end });
+        self
+    }
+
+    /// Use `clock` for the current time, instead of the system clock.
+    #[cfg(test)]
+    pub(crate) fn clock(mut self, clock: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        self.config.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Build the rewriter.
+    ///
+    /// # Panics
+    ///
+    /// Panics with a description of the problem if the options conflict, e.g.
+    /// [`stale_while_revalidate`](Self::stale_while_revalidate) without
+    /// [`track_inode`](Self::track_inode).
+    pub fn build(self) -> CachedCompression {
+        match self.try_build() {
+            Ok(cache) => cache,
+            Err(e) => panic!("invalid CachedCompression configuration: {e}"),
+        }
+    }
+
+    /// Build the rewriter, failing with [`Error::Invalid`] rather than
+    /// panicking if the options conflict, or the
+    /// [`manifest`](Self::manifest) is invalid.
+    pub fn try_build(mut self) -> Result<CachedCompression, Error> {
+        self.config.validate().map_err(Error::Invalid)?;
+        self.config.offered = Offered::of(&self.config);
+        self.config.filters = Filters::of(&self.config.include, &self.config.exclude);
+        Ok(CachedCompression::from_config(self.config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "stale_while_revalidate() requires track_inode()")]
+    fn stale_requires_inode() {
+        CachedCompression::builder()
+            .stale_while_revalidate()
+            .build();
+    }
+
+    #[test]
+    #[should_panic(expected = "initial level must be lower")]
+    fn tier_must_upgrade() {
+        CachedCompression::builder().recompress_tier(9, 1).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid file pattern `text/plain/x`")]
+    fn patterns_validated() {
+        CachedCompression::builder().exclude("text/plain/x").build();
+    }
+
+    #[test]
+    fn filters_from_figment() {
+        use rocket::figment::providers::{Format, Toml};
+
+        let figment = Figment::new().merge(Toml::string(
+            r#"
+            [compression]
+            include = ["text/*", "js"]
+            exclude = ["text/csv"]
+            "#,
+        ));
+        let cache = CachedCompression::builder()
+            .configure(&figment)
+            .unwrap()
+            .build();
+        let compressed = |path: &str| cache.config.compressible(Path::new(path));
+        assert!(compressed("static/index.txt"));
+        assert!(compressed("static/app.JS"));
+        assert!(!compressed("static/data.csv"));
+        assert!(!compressed("static/logo.png"));
+        let decision = cache.decide("gzip", Path::new("static/data.csv"));
+        assert_eq!(decision, crate::Decision::ServeIdentity);
+    }
+
+    #[test]
+    fn valid_options() {
+        CachedCompression::builder()
+            .track_inode()
+            .stale_while_revalidate()
+            .recompress_tier(1, 9)
+            .artifact_mode(0o644)
+            .build();
+    }
+
+    #[test]
+    fn preference_from_string() {
+        use rocket::figment::providers::{Format, Toml};
+
+        assert_eq!(
+            parse_preference(" deflate-raw,gzip, "),
+            Ok(vec![Algorithm::DeflateRaw, Algorithm::Gzip])
+        );
+        assert_eq!(
+            parse_preference("br,gzip,deflate"),
+            Err("unknown encoding `br`".into())
+        );
+        assert!(parse_preference(" , ").is_err());
+
+        let figment = Figment::new().merge(Toml::string(
+            r#"
+            [compression]
+            preference = "deflate-raw, gzip"
+            "#,
+        ));
+        let cache = CachedCompression::builder()
+            .configure(&figment)
+            .unwrap()
+            .build();
+        assert_eq!(
+            cache.config.algorithms(),
+            [Algorithm::DeflateRaw, Algorithm::Gzip]
+        );
+        let decision = cache.decide("gzip, deflate-raw", Path::new("static/index.txt"));
+        assert_eq!(decision, crate::Decision::Dispatch(Algorithm::DeflateRaw));
+
+        let figment = Figment::new().merge(Toml::string(
+            r#"
+            [compression]
+            preference = "gzip, zstd"
+            "#,
+        ));
+        assert!(CachedCompression::builder().configure(&figment).is_err());
+    }
+
+    #[test]
+    fn errors_by_kind() {
+        use rocket::figment::providers::{Format, Toml};
+
+        let invalid = CachedCompression::builder()
+            .retry(0, Duration::ZERO)
+            .try_build();
+        let message = "retry() needs at least one attempt";
+        assert!(matches!(invalid, Err(Error::Invalid(e)) if e == message));
+
+        let figment = Figment::new().merge(Toml::string("[compression]\ninclude = 3"));
+        let unreadable = CachedCompression::builder().configure(&figment);
+        assert!(matches!(unreadable, Err(Error::Config(_))));
+        let figment = Figment::new().merge(Toml::string("[compression]\npreference = \"br\""));
+        let unknown = CachedCompression::builder().configure(&figment);
+        assert!(matches!(unknown, Err(Error::Invalid(e)) if e == "unknown encoding `br`"));
+        assert!(CachedCompression::builder().try_build().is_ok());
+    }
+
+    #[test]
+    fn offered_precomputed() {
+        let orders: [&[Algorithm]; 4] = [
+            &[],
+            &[Algorithm::Gzip],
+            &[Algorithm::DeflateRaw],
+            &[Algorithm::DeflateRaw, Algorithm::Gzip],
+        ];
+        for order in orders {
+            let cache = CachedCompression::builder().prefer(order).build();
+            let config = &cache.config;
+            for algo in Algorithm::ALL {
+                let enabled = config.algorithms().contains(&algo);
+                assert_eq!(config.offered.enabled[algo as usize], enabled, "{order:?}");
+                let rank = config.preference.iter().position(|a| *a == algo);
+                assert_eq!(config.offered.rank[algo as usize], rank, "{order:?}");
+            }
+            let path = Path::new("static/index.txt");
+            let expected = match order.first() {
+                Some(first) => crate::Decision::Dispatch(*first),
+                None => crate::Decision::Dispatch(Algorithm::Gzip),
+            };
+            assert_eq!(cache.decide("gzip, deflate-raw, *;q=0.5", path), expected);
+        }
+    }
+}
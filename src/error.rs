@@ -0,0 +1,61 @@
+use std::{fmt, io};
+
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    Request,
+};
+
+/// Why a [`CachedCompression`](crate::CachedCompression) couldn't be built,
+/// or couldn't do what it was asked.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// The configuration couldn't be read from a figment.
+    Config(rocket::figment::Error),
+    /// The options don't make sense, as described.
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Config(e) => write!(f, "invalid configuration: {e}"),
+            Error::Invalid(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Config(e) => Some(e),
+            Error::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<rocket::figment::Error> for Error {
+    fn from(e: rocket::figment::Error) -> Self {
+        Error::Config(e)
+    }
+}
+
+/// Responds like the underlying [`io::Error`], or with a `500` otherwise.
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            Error::Io(e) => e.respond_to(req),
+            _ => Err(Status::InternalServerError),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::path::Path;
+
+use rocket::http::ContentType;
+
+use crate::content_type_from_path;
+
+/// A pattern selecting files by extension, like `js` or `.js`, or by media
+/// type, like `application/json` or `text/*`. Both are case-insensitive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Pattern {
+    Extension(String),
+    /// A top-level type, and a subtype unless it's `*`.
+    MediaType(String, Option<String>),
+}
+
+impl Pattern {
+    /// Parse `pattern`, describing why it's invalid if it is.
+    pub(crate) fn parse(pattern: &str) -> Result<Self, String> {
+        let valid = |part: &str| {
+            !part.is_empty() && part.bytes().all(|b| b.is_ascii_graphic() && b != b'/')
+        };
+        let parsed = match pattern.split_once('/') {
+            Some(("*", "*")) => None,
+            Some((top, "*")) if valid(top) && !top.contains('*') => {
+                Some(Pattern::MediaType(top.into(), None))
+            }
+            Some((top, sub)) if valid(top) && valid(sub) && !pattern.contains('*') => {
+                Some(Pattern::MediaType(top.into(), Some(sub.into())))
+            }
+            Some(_) => None,
+            None => {
+                let ext = pattern.strip_prefix('.').unwrap_or(pattern);
+                (valid(ext) && !ext.contains('*')).then(|| Pattern::Extension(ext.into()))
+            }
+        };
+        parsed.ok_or_else(|| format!("invalid file pattern `{pattern}`"))
+    }
+
+    /// Whether the file at `path` is selected.
+    pub(crate) fn matches(&self, path: &Path) -> bool {
+        let ext = path.extension().and_then(|e| e.to_str());
+        self.matches_kind(ext, content_type_from_path(path).as_ref())
+    }
+
+    /// Whether content with the extension `ext`, and of the type `ct`, is
+    /// selected.
+    fn matches_kind(&self, ext: Option<&str>, ct: Option<&ContentType>) -> bool {
+        match self {
+            Pattern::Extension(pattern) => ext.is_some_and(|e| e.eq_ignore_ascii_case(pattern)),
+            Pattern::MediaType(top, sub) => {
+                ct.is_some_and(|ct| match sub {
+                    Some(sub) => ct.top() == top && ct.sub() == sub,
+                    // Text underneath, whatever the top-level type, e.g. SVG
+                    None if top.eq_ignore_ascii_case("text") => {
+                        ct.top() == top || is_text_like(ct.sub().as_str())
+                    }
+                    None => ct.top() == top,
+                })
+            }
+        }
+    }
+}
+
+/// The `include` and `exclude` patterns, parsed once on build rather than
+/// for every request.
+#[derive(Debug, Default)]
+pub(crate) struct Filters {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filters {
+    /// The valid patterns among `include` and `exclude`, which were validated
+    /// on build.
+    pub(crate) fn of(include: &[String], exclude: &[String]) -> Self {
+        let parse = |patterns: &[String]| {
+            patterns
+                .iter()
+                .filter_map(|p| Pattern::parse(p).ok())
+                .collect()
+        };
+        Self {
+            include: parse(include),
+            exclude: parse(exclude),
+        }
+    }
+
+    /// Whether the file at `path` is selected: included, if any patterns are,
+    /// or otherwise not in a compressed media format, and not excluded.
+    pub(crate) fn selects(&self, path: &Path) -> bool {
+        let ext = path.extension().and_then(|e| e.to_str());
+        self.selects_kind(ext, content_type_from_path(path).as_ref())
+    }
+
+    /// Whether a body of the type `ct` is selected, like a file with the
+    /// type's usual extension would be.
+    pub(crate) fn selects_type(&self, ct: &ContentType) -> bool {
+        self.selects_kind(ct.extension().map(|e| e.as_str()), Some(ct))
+    }
+
+    fn selects_kind(&self, ext: Option<&str>, ct: Option<&ContentType>) -> bool {
+        let matches = |patterns: &[Pattern]| patterns.iter().any(|p| p.matches_kind(ext, ct));
+        let included = match self.include.is_empty() {
+            true => !ext.is_some_and(is_compressed_media),
+            false => matches(&self.include),
+        };
+        included && !matches(&self.exclude)
+    }
+}
+
+/// Extensions of media formats that are compressed already, so compressing
+/// them again only costs time.
+const COMPRESSED_MEDIA: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "webp", "avif", "woff", "woff2", "mp3", "mp4", "webm", "ogg",
+];
+
+/// Whether files with the extension `ext` are in a compressed media format.
+fn is_compressed_media(ext: &str) -> bool {
+    COMPRESSED_MEDIA.iter().any(|e| e.eq_ignore_ascii_case(ext))
+}
+
+/// Whether `sub` is a subtype with a `+xml` or `+json` structured syntax
+/// suffix, like `svg+xml`, so the content is text.
+fn is_text_like(sub: &str) -> bool {
+    let suffix = sub.rsplit_once('+').map(|(_, suffix)| suffix);
+    suffix.is_some_and(|s| s.eq_ignore_ascii_case("xml") || s.eq_ignore_ascii_case("json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_patterns() {
+        assert_eq!(Pattern::parse("js"), Ok(Pattern::Extension("js".into())));
+        assert_eq!(Pattern::parse(".js"), Ok(Pattern::Extension("js".into())));
+        assert_eq!(
+            Pattern::parse("text/*"),
+            Ok(Pattern::MediaType("text".into(), None))
+        );
+        assert_eq!(
+            Pattern::parse("application/json"),
+            Ok(Pattern::MediaType(
+                "application".into(),
+                Some("json".into())
+            ))
+        );
+        for invalid in [
+            "", ".", "*", "*/*", "*/json", "text/", "/json", "a/b/c", "te xt", "*.js",
+        ] {
+            assert!(Pattern::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn filters_select() {
+        let include = ["text/*".to_string(), "js".to_string()];
+        let exclude = ["text/csv".to_string()];
+        let filters = Filters::of(&include, &exclude);
+        assert!(filters.selects(Path::new("static/index.html")));
+        assert!(filters.selects(Path::new("static/app.js")));
+        assert!(!filters.selects(Path::new("static/data.csv")));
+        assert!(!filters.selects(Path::new("static/logo.png")));
+        // Unless something is included, compressed media never is
+        assert!(Filters::default().selects(Path::new("static/index.html")));
+        assert!(!Filters::default().selects(Path::new("static/logo.PNG")));
+        assert!(Filters::of(&["png".to_string()], &[]).selects(Path::new("static/logo.png")));
+    }
+
+    #[test]
+    fn filters_select_types() {
+        let include = ["application/*".to_string(), "html".to_string()];
+        let exclude = ["json".to_string()];
+        let filters = Filters::of(&include, &exclude);
+        assert!(filters.selects_type(&ContentType::WASM));
+        assert!(filters.selects_type(&ContentType::HTML));
+        assert!(!filters.selects_type(&ContentType::JSON));
+        assert!(!filters.selects_type(&ContentType::CSS));
+        assert!(Filters::default().selects_type(&ContentType::JSON));
+        assert!(!Filters::default().selects_type(&ContentType::PNG));
+    }
+}
@@ -1,38 +1,112 @@
 #[deny(missing_docs)]
 use std::{
+    borrow::Cow,
     fmt::Display,
-    io,
+    future::Future,
+    io::{self, Cursor},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+mod builder;
+mod error;
+mod filter;
+mod manifest;
+mod store;
+mod window;
+
 use dashmap::DashMap;
-use flate2::{Compress, Compression, Status};
+use flate2::{Compress, Compression, FlushCompress, GzBuilder, Status};
 use rocket::{
-    fs::rewrite::{Rewrite, Rewriter},
-    http::{ContentType, Header},
-    tokio::io::{AsyncReadExt, AsyncWriteExt}, trace::error,
+    fairing::{self, Fairing, Kind},
+    fs::rewrite::{File, Rewrite, Rewriter},
+    http::{ContentType, Header, HeaderMap},
+    response::{self, Responder, Response},
+    serde::Serialize,
+    tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+        task::JoinHandle,
+    },
+    trace::{debug, error, warn},
+    Build, Rocket,
 };
 
+pub use builder::CachedCompressionBuilder;
+use builder::Config;
+pub use error::Error;
+use manifest::Overrides;
+pub use store::{ArtifactStore, FileSystem};
+
+/// How many codings of an `Accept-Encoding` header are considered. Anything
+/// past these is ignored, so an oversized header can't make negotiation
+/// arbitrarily expensive.
+const MAX_CODINGS: usize = 32;
+
+/// The `tracing` target of every event logged here, so they can be filtered
+/// independently of the rest of the application.
+const TARGET: &str = "rocket_caching_layer";
+
 /// Supported compression algorithms
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Algorithm {
     Gzip,
+    /// Raw deflate, without the zlib header and trailer, negotiated with the
+    /// `deflate-raw` token. This matches what `DecompressionStream("deflate-raw")`
+    /// expects, but isn't a registered HTTP content coding, so browsers won't ask
+    /// for it on their own. Only useful for clients that request it explicitly.
+    ///
+    /// Copies are stored with a `.deflate` suffix.
+    DeflateRaw,
 }
 
 impl Algorithm {
-    fn name(&self) -> &'static str {
+    /// Every supported algorithm.
+    pub(crate) const ALL: [Algorithm; 2] = [Algorithm::Gzip, Algorithm::DeflateRaw];
+
+    /// The base-2 logarithm of the window size gzip streams are compressed
+    /// with, the largest zlib supports.
+    pub const WINDOW_BITS: u8 = 15;
+
+    /// The token identifying this algorithm in `Accept-Encoding` and
+    /// `Content-Encoding` headers.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Gzip => "gzip",
+            Algorithm::DeflateRaw => "deflate-raw",
+        }
+    }
+
+    /// The suffix appended to a file's name to store its compressed copy, e.g.
+    /// `index.html.gzip`. This needn't match the HTTP token, but differs for
+    /// every algorithm, ignoring case, so their copies never overwrite each
+    /// other.
+    pub fn extension(&self) -> &'static str {
         match self {
             Algorithm::Gzip => "gzip",
+            Algorithm::DeflateRaw => "deflate",
         }
     }
 
+    /// The algorithm for a coding token, including the legacy `x-gzip` alias
+    /// some clients still send. Responses always name the token from `name()`.
     fn from_name(name: &str) -> Option<Self> {
         match name {
-            "gzip" => Some(Self::Gzip),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate-raw" => Some(Self::DeflateRaw),
             _ => None,
         }
     }
+
+    fn compressor(&self, level: u32) -> Compress {
+        match self {
+            Algorithm::Gzip => Compress::new_gzip(Compression::new(level), Self::WINDOW_BITS),
+            Algorithm::DeflateRaw => Compress::new(Compression::new(level), false),
+        }
+    }
 }
 
 impl Display for Algorithm {
@@ -41,253 +115,6059 @@ impl Display for Algorithm {
     }
 }
 
+/// How to respond to a request for a file that the client would be served
+/// compressed, if a copy were cached yet, see
+/// [`CachedCompressionBuilder::cold_miss_policy`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColdMissPolicy {
+    /// Serve the original file.
+    #[default]
+    ServeIdentity,
+    /// Respond with `503 Service Unavailable`, and a `Retry-After` header
+    /// asking the client to try again after this long, rounded up to a whole
+    /// number of seconds.
+    RetryAfter(Duration),
+}
+
+/// Marks a request answered with a cold miss, for `on_response`.
+struct ColdMiss(Option<Duration>);
+
+/// The outcome of negotiating a response for a file, see
+/// [`CachedCompression::decide`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// A compressed copy is cached, and should be served.
+    ServeCompressed(Algorithm),
+    /// The client doesn't accept any supported encoding, or the file isn't
+    /// worth compressing, so the original file should be served.
+    ServeIdentity,
+    /// The client accepts this encoding, but nothing is cached yet. The original
+    /// file should be served, while a compressed copy is generated.
+    Dispatch(Algorithm),
+    /// A compressed copy is cached, but was generated from a file that has
+    /// since been replaced. It should be served, while a fresh copy is
+    /// generated.
+    ServeStale(Algorithm),
+    /// The client's preferred encoding isn't cached yet, but another encoding
+    /// it accepts is. That one should be served, while a copy in the preferred
+    /// encoding is generated.
+    Fallback {
+        /// The cached encoding to serve.
+        serve: Algorithm,
+        /// The preferred encoding to generate.
+        dispatch: Algorithm,
+    },
+}
+
+/// Which file a request would be answered with, see
+/// [`CachedCompression::resolve`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolvedTarget {
+    /// The cached copy at `artifact`, encoded with `algo`.
+    Compressed { algo: Algorithm, artifact: PathBuf },
+    /// The original file.
+    Identity,
+}
+
+enum Cached {
+    Missing,
+    Stale,
+    Fresh,
+    /// Known not to be worth compressing.
+    Skipped,
+}
+
+pub(crate) type RequestPredicate = Box<dyn Fn(&rocket::Request<'_>) -> bool + Send + Sync>;
+pub(crate) type PathPredicate = Box<dyn Fn(&str) -> bool + Send + Sync>;
+pub(crate) type LevelBySize = Box<dyn Fn(u64) -> u32 + Send + Sync>;
+pub(crate) type ErrorHook = Box<dyn Fn(&Path, &io::Error) + Send + Sync>;
+pub(crate) type PostProcess = Box<dyn Fn(&mut Vec<u8>) + Send + Sync>;
+
+/// Request and compression counts, see [`CachedCompression::counters`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// Responses served from a compressed copy.
+    pub hits: u64,
+    /// Responses for clients accepting a supported encoding that were served
+    /// the original file, because no compressed copy was available yet.
+    pub misses: u64,
+    /// Compressed copies successfully generated.
+    pub compressions: u64,
+    /// Compressions that failed.
+    pub failures: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    compressions: AtomicU64,
+    failures: AtomicU64,
+    /// Compressions running or queued, for `max_pending()` and `shutdown()`.
+    scheduled: AtomicUsize,
+    /// Notified when `scheduled` drops to zero.
+    idle: rocket::tokio::sync::Notify,
+    /// How well each algorithm compresses files, by extension, for `adaptive()`.
+    ratios: DashMap<(String, Algorithm), Ratio>,
+}
+
+/// A running average of compressed size relative to the original.
+#[derive(Default)]
+struct Ratio {
+    mean: f64,
+    samples: u64,
+}
+
+/// How many files of a type each algorithm compresses before `adaptive()`
+/// trusts its average.
+const ADAPTIVE_SAMPLES: u64 = 3;
+
+impl Stats {
+    /// Record that `algo` compressed `path` from `original` to `compressed` bytes.
+    fn learn(&self, path: &Path, algo: Algorithm, original: u64, compressed: u64) {
+        let Some(ext) = extension_key(path) else {
+            return;
+        };
+        let ratio = compressed as f64 / original.max(1) as f64;
+        let mut entry = self.ratios.entry((ext, algo)).or_default();
+        entry.samples += 1;
+        entry.mean += (ratio - entry.mean) / entry.samples as f64;
+    }
+
+    /// The average ratio `algo` achieves on files with extension `ext`, once
+    /// it has enough samples.
+    fn mean_ratio(&self, ext: &str, algo: Algorithm) -> Option<f64> {
+        let ratio = self.ratios.get(&(ext.to_string(), algo))?;
+        (ratio.samples >= ADAPTIVE_SAMPLES).then_some(ratio.mean)
+    }
+}
+
+/// The extension of `path`, as a key for learned ratios.
+fn extension_key(path: &Path) -> Option<String> {
+    Some(path.extension()?.to_str()?.to_ascii_lowercase())
+}
+
+#[derive(Default)]
 struct Info {
     compressions: Vec<Algorithm>,
-    pending: Vec<Algorithm>,
+    /// Shared with the running compressions, which release their algorithm
+    /// when done.
+    pending: Arc<Pending>,
+    /// Device and inode of the source file the `compressions` were generated from.
+    inode: Option<(u64, u64)>,
+    /// The source isn't worth compressing, e.g. because it's empty.
+    skip: bool,
+    /// Algorithms whose copies saved too little, for `min_savings_ratio()`.
+    marginal: Vec<Algorithm>,
+    /// Sizes of the source and the copy, when each copy was last generated.
+    sizes: Vec<(Algorithm, u64, u64)>,
+    /// When each copy was last generated.
+    generated: Vec<(Algorithm, Instant)>,
+    /// Algorithms that failed to compress the source, and the one from the
+    /// `fallback()` chain that replaced them, once one has.
+    failed: Vec<Algorithm>,
+    fallback: Option<Algorithm>,
+    /// Requests for the source without a copy to serve, for `min_requests()`.
+    requests: u64,
+    /// With `in_memory_hits()`, where the store serves each copy from, and
+    /// the source as last checked in the background.
+    served_from: Vec<(Algorithm, PathBuf)>,
+    seen: Option<Seen>,
+    /// With `file_policies()`, the policy of the source, and when the source
+    /// last changed as it was read.
+    policy: Option<(Stamp, Overrides)>,
+}
+
+/// The identity and size of a source file, when it was last checked.
+#[derive(Clone, Copy)]
+struct Seen {
+    id: Option<(u64, u64)>,
+    len: u64,
+}
+
+/// Report that the cached copy at `artifact` is unusable, to the
+/// `on_cache_error()` hook if one is set.
+fn report_unusable(config: &Config, artifact: &Path, err: &io::Error) {
+    match &config.on_cache_error {
+        Some(hook) => hook(artifact, err),
+        None => warn!(target: TARGET, ?err, "Cached copy {} is unusable", artifact.display()),
+    }
+}
+
+/// Note where the store serves the copy of `path` for `algo` from, for
+/// `in_memory_hits()`.
+fn remember_copy(map: &DashMap<PathBuf, Info>, config: &Config, path: &Path, algo: Algorithm) {
+    let artifact = artifact_path(path, algo, config.version());
+    match config.store().local_path(&artifact) {
+        Ok(local) => {
+            if let Some(mut v) = map.get_mut(path) {
+                v.served_from.retain(|(a, _)| *a != algo);
+                v.served_from.push((algo, local.into_owned()));
+            }
+        }
+        // Left for the next request to notice
+        Err(e) => debug!(target: TARGET, ?e, "Cached copy {} is unusable", artifact.display()),
+    }
+}
+
+/// Check every cached copy in `map` is still stored and usable, forgetting
+/// and reporting those that aren't, and note what each source is like now,
+/// for `in_memory_hits()`.
+async fn revalidate(map: &DashMap<PathBuf, Info>, config: &Config) {
+    let cached: Vec<_> = map
+        .iter()
+        .filter(|v| !v.compressions.is_empty())
+        .map(|v| (v.key().clone(), v.compressions.clone()))
+        .collect();
+    for (path, algos) in cached {
+        let track_inode = config.track_inode;
+        let meta = rocket::tokio::fs::metadata(&path).await.ok();
+        let seen = meta.as_ref().map(|meta| Seen::of(meta, track_inode));
+        let policy = match &meta {
+            Some(meta) if config.file_policies => Some((Stamp::of(meta), config.policy(&path))),
+            _ => None,
+        };
+        let mut unusable = vec![];
+        for algo in algos {
+            let artifact = artifact_path(&path, algo, config.version());
+            let usable = match config.store().exists(&artifact).await {
+                Ok(true) => config.store().local_path(&artifact).map(drop),
+                Ok(false) => Err(io::Error::from(io::ErrorKind::NotFound)),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = usable {
+                report_unusable(config, &artifact, &e);
+                unusable.push(algo);
+            }
+        }
+        if let Some(mut v) = map.get_mut(&path) {
+            v.compressions.retain(|a| !unusable.contains(a));
+            v.served_from.retain(|(a, _)| !unusable.contains(a));
+            v.seen = seen.or(v.seen);
+            v.policy = policy.or(v.policy);
+        }
+    }
+}
+
+/// The size of the source when its copy for `algo` was generated.
+fn recorded_original(info: &Info, algo: Algorithm) -> Option<u64> {
+    info.sizes
+        .iter()
+        .find(|(a, ..)| *a == algo)
+        .map(|(_, original, _)| *original)
+}
+
+/// What the file at `path` is like now, with a single `stat`, only
+/// identified if `track_inode`.
+fn observe(path: &Path, track_inode: bool) -> Option<Seen> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some(Seen::of(&meta, track_inode))
+}
+
+impl Seen {
+    fn of(meta: &std::fs::Metadata, track_inode: bool) -> Self {
+        Self {
+            id: if track_inode { id_of(meta) } else { None },
+            len: meta.len(),
+        }
+    }
+}
+
+/// When a source file last changed, as far as its policy is concerned.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    /// Setting an extended attribute only changes the status change time.
+    changed: Option<(i64, i64)>,
+}
+
+impl Stamp {
+    fn of(meta: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let changed = {
+            use std::os::unix::fs::MetadataExt;
+
+            Some((meta.ctime(), meta.ctime_nsec()))
+        };
+        #[cfg(not(unix))]
+        let changed = None;
+        Self {
+            modified: meta.modified().ok(),
+            len: meta.len(),
+            changed,
+        }
+    }
+}
+
+/// The policy of the file at `path`, with `file_policies()`, only read again
+/// once the file changed since it last was. What's read is only kept in `map`
+/// if `remember`.
+fn policy_of(
+    map: &DashMap<PathBuf, Info>,
+    config: &Config,
+    path: &Path,
+    remember: bool,
+) -> Overrides {
+    if !config.file_policies {
+        return Overrides::default();
+    }
+    let Ok(meta) = std::fs::metadata(path) else {
+        return config.policy(path);
+    };
+    let stamp = Stamp::of(&meta);
+    let cached = map.get(path).and_then(|info| info.policy);
+    if let Some((_, policy)) = cached.filter(|(read, _)| *read == stamp) {
+        return policy;
+    }
+    let policy = config.policy(path);
+    if remember {
+        map.entry(path.to_path_buf()).or_default().policy = Some((stamp, policy));
+    }
+    policy
 }
 
+impl Info {
+    /// Record the sizes of a copy for `algo` just generated, and that it was
+    /// generated now.
+    fn record_sizes(&mut self, algo: Algorithm, original: u64, compressed: u64) {
+        self.sizes.retain(|(a, ..)| *a != algo);
+        self.sizes.push((algo, original, compressed));
+        self.generated.retain(|(a, _)| *a != algo);
+        self.generated.push((algo, Instant::now()));
+    }
+}
+
+/// The progress of warming the cache, see [`CachedCompression::warm`].
+///
+/// Responds with itself as JSON, e.g. from an admin route:
+///
+/// ```rust,no_run
+/// # use rocket::{post, State};
+/// # use rocket_caching_layer::{CachedCompression, Error, WarmStatus};
+/// #[post("/admin/warm")]
+/// async fn warm(cache: &State<CachedCompression>) -> Result<WarmStatus, Error> {
+///     cache.warm("static").await
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WarmStatus {
+    /// Files found that are worth compressing.
+    pub total: usize,
+    /// Files with compressed copies still being generated.
+    pub queued: usize,
+    /// Files with every compressed copy already generated.
+    pub completed: usize,
+}
+
+impl<'r> Responder<'r, 'static> for WarmStatus {
+    fn respond_to(self, _: &'r rocket::Request<'_>) -> response::Result<'static> {
+        let json = format!(
+            r#"{{"total":{},"queued":{},"completed":{}}}"#,
+            self.total, self.queued, self.completed
+        );
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(json.len(), Cursor::new(json))
+            .ok()
+    }
+}
+
+/// See [`CachedCompression::global`].
+static GLOBAL: OnceLock<CachedCompression> = OnceLock::new();
+
 /// A rewriter for `FileServer`, that implements cached compression.
 ///
 /// Implements caching by storing a compressed copy of the file to be served.
 /// When a request is made for a file for the first time, a task is dispatched
 /// to generate a compressed copy of the file, and future requests (after the
 /// compression task has completed) will send the compressed version.
+///
+/// Empty files are never compressed, since they can't get any smaller, and are
+/// always served as-is.
+///
+/// `HEAD` requests are rewritten just like `GET` requests, so they report the
+/// same `Content-Encoding`, `Content-Type` and `Content-Length`.
+///
+/// Clones share the same cache. Dropping the last one doesn't stop
+/// compressions already started, which finish in the background, and leaves
+/// every compressed copy on disk. Use [`shutdown`](Self::shutdown) to wait for
+/// them, and optionally delete the copies.
+#[derive(Clone)]
 pub struct CachedCompression {
     map: Arc<DashMap<PathBuf, Info>>,
+    config: Arc<Config>,
+    stats: Arc<Stats>,
+    deferred: Arc<Mutex<Deferred>>,
+    /// Whether the `in_memory_hits()` task is running.
+    revalidating: Arc<AtomicBool>,
 }
 
 impl CachedCompression {
+    /// The compression level files are compressed at, unless set otherwise,
+    /// e.g. with [`recompress_tier`](CachedCompressionBuilder::recompress_tier).
+    pub const DEFAULT_LEVEL: u32 = 9;
+
+    /// The size, in bytes, of the buffers files are compressed through, unless
+    /// given to [`compress_file`](Self::compress_file).
+    pub const BUFFER_SIZE: usize = 1024;
+
     /// Create a default caching compression rewrite. Should be added at or near
     /// the end of the chain.
+    ///
+    /// Shortcut for `CachedCompression::builder().build()`.
     pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    /// Create a builder, to configure the rewrite before adding it to the chain.
+    pub fn builder() -> CachedCompressionBuilder {
+        CachedCompressionBuilder::default()
+    }
+
+    /// Set the process-wide instance returned by [`global`](Self::global), so
+    /// every `FileServer` shares one cache:
+    ///
+    /// ```rust,no_run
+    /// # use rocket::fs::FileServer;
+    /// # use rocket_caching_layer::CachedCompression;
+    /// CachedCompression::init_global(CachedCompression::builder().track_inode().build())
+    ///     .expect("initialized once");
+    /// rocket::build()
+    ///     .mount("/", FileServer::new("static").rewrite(CachedCompression::global().clone()))
+    ///     .mount("/docs", FileServer::new("docs").rewrite(CachedCompression::global().clone()));
+    /// ```
+    ///
+    /// Fails, returning `cache`, if the global instance was already set, or
+    /// already used.
+    pub fn init_global(cache: CachedCompression) -> Result<(), CachedCompression> {
+        GLOBAL.set(cache)
+    }
+
+    /// The process-wide instance set by [`init_global`](Self::init_global).
+    /// Clones of it share its cache. If it was never set, it's created with
+    /// the default options on first use.
+    pub fn global() -> &'static CachedCompression {
+        GLOBAL.get_or_init(CachedCompression::new)
+    }
+
+    pub(crate) fn from_config(config: Config) -> Self {
         Self {
             map: Arc::new(DashMap::new()),
+            config: Arc::new(config),
+            stats: Arc::default(),
+            deferred: Arc::default(),
+            revalidating: Arc::default(),
         }
     }
 
-    fn get_valid(&self, req: &rocket::Request<'_>) -> Option<Algorithm> {
-        req.headers()
-            .get("Accept-Encoding")
-            .flat_map(|v| v.split(|c| c == ','))
-            .filter_map(|coding| {
-                let mut parts = coding.split(';');
-                let name = parts.next()?;
-                for (p, val) in parts.filter_map(|p| p.split_once('=')) {
-                    let val: f32 = val.trim().parse().unwrap_or(0.);
-                    if val == 0. && p.trim() == "q" {
-                        return None;
-                    }
+    /// Serve the cached copy of `file` for `algo`, provided the store can still
+    /// provide it. Otherwise, report the problem, forget the copy and schedule
+    /// a new one, and leave `file` pointing at the original.
+    ///
+    /// With `in_memory_hits()`, the store isn't asked again once it provided
+    /// the copy, which is checked in the background instead.
+    fn serve_cached(&self, file: &mut File<'_>, algo: Algorithm) {
+        let artifact = artifact_path(&file.path, algo, self.config.version());
+        let remembered = self.config.revalidate.and_then(|_| {
+            let info = self.map.get(file.path.as_ref())?;
+            info.served_from
+                .iter()
+                .find(|(a, _)| *a == algo)
+                .map(|(_, local)| local.clone())
+        });
+        // Only an owned path is kept, so `artifact` can be moved if borrowed
+        let local = match remembered {
+            Some(local) => Ok(Some(local)),
+            None => self
+                .config
+                .store()
+                .local_path(&artifact)
+                .map(|local| match local {
+                    Cow::Owned(local) => Some(local),
+                    Cow::Borrowed(_) => None,
+                }),
+        };
+        let err = match local {
+            Ok(local) => {
+                let local = local.unwrap_or(artifact);
+                self.stats.hits.fetch_add(1, Ordering::Relaxed);
+                if self.config.debug_headers {
+                    self.add_debug_headers(file, algo);
                 }
-                Some(name.trim())
-            })
-            .filter_map(|coding| Algorithm::from_name(coding))
-            .nth(0)
+                serve_compressed(file, algo, local);
+                return;
+            }
+            Err(e) => e,
+        };
+        report_unusable(&self.config, &artifact, &err);
+        if let Some(mut info) = self.map.get_mut(file.path.as_ref()) {
+            info.compressions.retain(|a| *a != algo);
+        }
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        self.dispatch(algo, file.path.clone().into_owned());
     }
 
-    fn dispatch(&self, algo: Algorithm, path: PathBuf) {
-        let map = self.map.clone();
-        rocket::tokio::spawn(async move {
-            {
-                let mut v = map.entry(path.clone()).or_insert(Info {
-                    compressions: vec![],
-                    pending: vec![],
-                });
-                if v.pending.contains(&algo) {
-                    return;
+    /// Add `X-Compression-Ratio` and `X-Cache-Age` headers to `file`, for its
+    /// copy for `algo`.
+    fn add_debug_headers(&self, file: &mut File<'_>, algo: Algorithm) {
+        let Some(info) = self.map.get(file.path.as_ref()) else {
+            return;
+        };
+        if let Some((_, original, compressed)) = info.sizes.iter().find(|(a, ..)| *a == algo) {
+            let ratio = *compressed as f64 / (*original).max(1) as f64;
+            file.headers
+                .replace(Header::new("X-Compression-Ratio", format!("{ratio:.3}")));
+        }
+        if let Some((_, at)) = info.generated.iter().find(|(a, _)| *a == algo) {
+            let age = at.elapsed().as_secs();
+            file.headers
+                .replace(Header::new("X-Cache-Age", age.to_string()));
+        }
+    }
+
+    /// Regenerate every cached copy with the current settings, e.g. after
+    /// raising the compression level, and wait for all of them to complete.
+    /// Each copy is replaced atomically, so the old one is served until then.
+    ///
+    /// Like requests, this respects [`max_pending`], though by waiting for
+    /// fewer compressions to be pending rather than skipping copies, and
+    /// [`compress_window`], queuing copies outside it without waiting for
+    /// them. Nothing is regenerated with [`serve_only`].
+    ///
+    /// [`max_pending`]: CachedCompressionBuilder::max_pending
+    /// [`compress_window`]: CachedCompressionBuilder::compress_window
+    /// [`serve_only`]: CachedCompressionBuilder::serve_only
+    pub async fn recompress_all(&self) {
+        // Collected first, so no shard is locked while the tasks start
+        let mut jobs = vec![];
+        for entry in self.map.iter() {
+            jobs.extend(
+                entry
+                    .compressions
+                    .iter()
+                    .map(|algo| (*algo, entry.key().clone())),
+            );
+        }
+        if self.config.serve_only {
+            return;
+        }
+        let mut tasks = vec![];
+        for (algo, path) in jobs {
+            if let Some(max) = self.config.max_pending {
+                while self.stats.scheduled.load(Ordering::Relaxed) >= max {
+                    rocket::tokio::time::sleep(PENDING_POLL).await;
                 }
-                v.pending.push(algo);
-                drop(v);
             }
-            let new_name = format!("{}.{algo}", path.file_name().unwrap().to_str().unwrap());
-            let new_path = path.with_file_name(new_name);
-            let compressor = match algo {
-                Algorithm::Gzip => Compress::new_gzip(Compression::new(9), 15),
-            };
+            tasks.extend(self.start(algo, path));
+        }
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
 
-            let success = match Self::compress(compressor, &path, &new_path).await {
-                Ok(()) => true,
-                Err(e) => {
-                    error!(?e, "Error when compressing file {}", path.display());
-                    false
-                }
+    /// Generate compressed copies of every file under `root`, the directory the
+    /// `FileServer` serves, ahead of any requests for them. Copies are
+    /// generated in the background, this only queues them.
+    ///
+    /// Calling this again is cheap, and reports how far along the earlier
+    /// call is.
+    ///
+    /// Fails with [`Error::Io`] if `root` can't be read.
+    pub async fn warm(&self, root: impl AsRef<Path>) -> Result<WarmStatus, Error> {
+        let mut status = WarmStatus::default();
+        for (path, meta) in files_under(root.as_ref()).await? {
+            if !self.worth_warming(&path) || meta.len() == 0 {
+                continue;
+            }
+            status.total += 1;
+            let missing: Vec<_> = self
+                .algorithms_for(&path)
+                .filter(|algo| !matches!(self.cached(&path, *algo), Cached::Fresh))
+                .collect();
+            if missing.is_empty() {
+                status.completed += 1;
+                continue;
+            }
+            status.queued += 1;
+            for algo in missing {
+                // Left to run in the background, even with `serve_only()`
+                let _ = self.start(algo, path.clone());
+            }
+        }
+        Ok(status)
+    }
+
+    /// Delete compressed copies under `dir` whose source file no longer
+    /// exists, e.g. left behind by files removed in a deploy, returning how
+    /// many were deleted.
+    ///
+    /// Only copies kept by the default [`FileSystem`] store are found. To be
+    /// safe, a file only counts as a copy if its name is another file name,
+    /// itself with an extension, followed by a compressed copy's suffix, like
+    /// `app.js.gzip`. Gzip copies must also start with the gzip magic bytes,
+    /// so unrelated files like `backup.tar.gzip` aren't mistaken for them.
+    /// With a [`cache_version`](CachedCompressionBuilder::cache_version),
+    /// copies stored under other versions are deleted too.
+    pub async fn prune_orphans(&self, dir: impl AsRef<Path>) -> io::Result<usize> {
+        let mut pruned = 0;
+        for (path, _) in files_under(dir.as_ref()).await? {
+            let Some((source, algo)) = source_of(&path, self.config.version()) else {
+                continue;
             };
-            {
-                let mut v = map.entry(path.clone()).or_insert(Info {
-                    compressions: vec![],
-                    pending: vec![],
-                });
-                v.pending.retain(|a| *a != algo);
-                if success {
-                    v.compressions.push(algo);
-                }
-                drop(v);
+            if rocket::tokio::fs::try_exists(&source).await? {
+                continue;
             }
-        });
+            if algo == Algorithm::Gzip && !has_gzip_magic(&path).await? {
+                continue;
+            }
+            match rocket::tokio::fs::remove_file(&path).await {
+                Ok(()) => pruned += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            self.map.remove(&source);
+        }
+        Ok(pruned)
     }
 
-    async fn compress(mut compressor: Compress, path: &Path, new_path: &Path) -> io::Result<()> {
-        // This isn't the ideal API to be using, but flate2 only provides sync APIs, so I have to
-        // deal with the async files for it.
-        let mut input = rocket::tokio::fs::File::open(path).await?;
-        let mut output = rocket::tokio::fs::File::create(new_path).await?;
-        let mut input_buf = [0u8; 1024];
-        let mut output_buf = [0u8; 1024];
-        loop {
-            let size = input.read(&mut input_buf).await?;
-            if size == 0 {
-                loop {
-                    let start_out = compressor.total_out();
-                    match compressor.compress(&[], &mut output_buf, flate2::FlushCompress::Finish) {
-                        Ok(Status::Ok) => {
-                            let out_size = compressor.total_out() - start_out;
+    /// Compress the file at `path` with `algo`, and only return once done,
+    /// rather than in the background like [`register`](Self::register), e.g.
+    /// so the copy is ready before launching, or in tests. Returns whether a
+    /// fresh copy is cached, which it isn't if compressing failed, or the file
+    /// isn't worth compressing. If the same compression is already running,
+    /// this waits for it instead.
+    ///
+    /// Unlike requests, this ignores `max_pending()` and `compress_window()`.
+    pub async fn warm_file(&self, path: impl Into<PathBuf>, algo: Algorithm) -> bool {
+        let path = path.into();
+        let _ticket = Ticket::new(&self.stats);
+        self.compression(algo, path.clone()).await;
+        while self.is_pending(&path, algo) {
+            rocket::tokio::time::sleep(PENDING_POLL).await;
+        }
+        matches!(self.cached(&path, algo), Cached::Fresh)
+    }
 
-                            output.write_all(&output_buf[..out_size as usize]).await?;
-                        }
-                        Ok(Status::BufError) => {
-                            return Err(io::Error::new(io::ErrorKind::InvalidData, ""))
-                        }
-                        Ok(Status::StreamEnd) => break,
-                        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "")),
-                    }
-                }
+    /// Compress the file at `path` with every algorithm offered right away,
+    /// rather than on the first request for it, e.g. once an upload has been
+    /// moved into the directory the `FileServer` serves. `path` must be the
+    /// one the `FileServer` maps requests for the file to, like `static/a.png`
+    /// for `FileServer::new("static")`. Any existing copy is replaced.
+    ///
+    /// Like requests, this respects [`max_pending`] and [`compress_window`].
+    /// Files that wouldn't be compressed on request, e.g. excluded ones, are
+    /// ignored.
+    ///
+    /// [`max_pending`]: CachedCompressionBuilder::max_pending
+    /// [`compress_window`]: CachedCompressionBuilder::compress_window
+    pub fn register(&self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        if !self.worth_warming(&path) {
+            return;
+        }
+        for algo in self.algorithms_for(&path) {
+            let _ = self.start(algo, path.clone());
+        }
+    }
+
+    /// The algorithms the file at `path` is compressed with, most preferred
+    /// first.
+    fn algorithms_for(&self, path: &Path) -> impl Iterator<Item = Algorithm> + '_ {
+        let only = self.overrides(path, true).algorithm;
+        let offered = self.config.algorithms().iter().copied();
+        offered.filter(move |algo| only.is_none_or(|only| only == *algo))
+    }
+
+    /// Whether `warm` should compress the file at `path`, rather than skip it
+    /// as a compressed copy, a temporary file, or something not compressed
+    /// when requested.
+    fn worth_warming(&self, path: &Path) -> bool {
+        let ext = path.extension().unwrap_or_default();
+        let generated = ext == "tmp"
+            || Algorithm::ALL.iter().any(|a| ext == a.extension())
+            || (ext == "meta" && source_of(&path.with_extension(""), None).is_some());
+        !generated && archive_content_type(path).is_none() && self.config.compressible(path)
+    }
+
+    /// Block the current thread for up to `wait`, until a fresh copy of `path`
+    /// for `algo` is cached. Returns whether one is.
+    ///
+    /// `rewrite` is synchronous, so this uses `block_in_place`, which hands
+    /// this worker's other tasks to another thread while waiting. That's only
+    /// possible on the multi-threaded runtime, so this doesn't wait elsewhere.
+    fn wait_for(&self, path: &Path, algo: Algorithm, wait: Duration) -> bool {
+        use rocket::tokio::runtime::{Handle, RuntimeFlavor};
+
+        let multi_thread = Handle::try_current()
+            .is_ok_and(|handle| handle.runtime_flavor() == RuntimeFlavor::MultiThread);
+        if !multi_thread {
+            return false;
+        }
+        let deadline = Instant::now() + wait;
+        rocket::tokio::task::block_in_place(|| loop {
+            if matches!(self.cached(path, algo), Cached::Fresh) {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            std::thread::sleep((deadline - now).min(PENDING_POLL));
+        })
+    }
+
+    /// Wait for every dispatched compression to complete, including any
+    /// dispatched in the meantime, e.g. before tearing down a server or test.
+    /// Compressions queued for the [compression window], and upgrades queued
+    /// by [`recompress_tier`], are dropped instead. If `remove_copies` is set,
+    /// every cached copy is then deleted, as by
+    /// [`invalidate_prefix`](Self::invalidate_prefix).
+    ///
+    /// [compression window]: CachedCompressionBuilder::compress_window
+    /// [`recompress_tier`]: CachedCompressionBuilder::recompress_tier
+    pub async fn shutdown(&self, remove_copies: bool) {
+        {
+            let mut deferred = self.deferred.lock().unwrap();
+            std::mem::take(&mut deferred.jobs);
+            deferred.upgrades.clear();
+        }
+        loop {
+            // Created before checking, so a notification in between isn't lost
+            let idle = self.stats.idle.notified();
+            if self.stats.scheduled.load(Ordering::Acquire) == 0 {
                 break;
             }
-            let mut rem = &input_buf[..size];
-            while rem.len() > 0 {
-                let start_in = compressor.total_in();
-                let start_out = compressor.total_out();
-                match compressor.compress(rem, &mut output_buf, flate2::FlushCompress::None) {
-                    Ok(Status::Ok) => {
-                        let in_size = compressor.total_in() - start_in;
-                        let out_size = compressor.total_out() - start_out;
+            idle.await;
+        }
+        if remove_copies {
+            // Every path starts with the empty prefix
+            self.invalidate_prefix("").await;
+        }
+    }
 
-                        output.write_all(&output_buf[..out_size as usize]).await?;
-                        rem = &rem[in_size as usize..];
-                    }
-                    Ok(Status::BufError) => {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, ""))
-                    }
-                    Ok(Status::StreamEnd) => todo!("This should never happen when compressing"),
-                    Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "")),
+    /// Forget the cached copies of every file under `prefix`, and delete them
+    /// from disk, e.g. after redeploying that directory. Copies are generated
+    /// again on the next request for each file.
+    ///
+    /// `prefix` is matched by whole path components, so `static/js` doesn't
+    /// cover `static/json`.
+    pub async fn invalidate_prefix(&self, prefix: impl AsRef<Path>) {
+        let prefix = prefix.as_ref();
+        let mut removed = vec![];
+        self.map.retain(|path, info| {
+            if !path.starts_with(prefix) {
+                return true;
+            }
+            let version = self.config.version();
+            let algos = info.compressions.iter();
+            removed.extend(algos.map(|algo| artifact_path(path, *algo, version)));
+            false
+        });
+        for artifact in removed {
+            match self.config.store().remove(&artifact).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    warn!(target: TARGET, ?e, "Failed to remove cached copy {}", artifact.display())
                 }
+                _ => (),
+            }
+            if self.config.write_metadata {
+                Self::remove_metadata(&artifact, &self.config).await;
             }
         }
-        // Note: this will only be executed if the above succeeds.
-        Ok(())
     }
-}
 
-// This might be a good addition to `ContentType` itself
-fn content_type_from_path(path: impl AsRef<Path>) -> Option<ContentType> {
-    ContentType::from_extension(path.as_ref().extension()?.to_str()?)
-}
+    /// A snapshot of how many requests have been served from the cache, and
+    /// how many compressions have run, since this rewriter was created.
+    pub fn counters(&self) -> Counters {
+        Counters {
+            hits: self.stats.hits.load(Ordering::Relaxed),
+            misses: self.stats.misses.load(Ordering::Relaxed),
+            compressions: self.stats.compressions.load(Ordering::Relaxed),
+            failures: self.stats.failures.load(Ordering::Relaxed),
+        }
+    }
 
-impl Rewriter for CachedCompression {
-    fn rewrite<'h>(
+    /// Whether a compressed copy of the file at `path`, as served by the
+    /// `FileServer` (e.g. `static/index.txt`), has been generated for `algo`.
+    ///
+    /// This doesn't check whether the copy is stale, or still on disk.
+    pub fn is_cached(&self, path: &Path, algo: Algorithm) -> bool {
+        self.map
+            .get(path)
+            .is_some_and(|info| info.compressions.contains(&algo))
+    }
+
+    /// Whether a compressed copy of the file at `path` is being generated for
+    /// `algo`, including recompression at a higher level once it starts.
+    pub fn is_pending(&self, path: &Path, algo: Algorithm) -> bool {
+        self.map
+            .get(path)
+            .is_some_and(|info| info.pending.contains(algo))
+    }
+
+    /// Respond with `body`, compressed on the fly if the request accepts a
+    /// supported encoding. Nothing is cached, so this suits dynamic content
+    /// like API responses:
+    ///
+    /// ```rust,no_run
+    /// # use rocket::{get, http::ContentType, State};
+    /// # use rocket_caching_layer::{CachedCompression, Compressed};
+    /// #[get("/data")]
+    /// fn data(cache: &State<CachedCompression>) -> Compressed {
+    ///     cache.respond(r#"{"hello":"world"}"#, ContentType::JSON)
+    /// }
+    /// ```
+    ///
+    /// Negotiation happens when the response is sent, so it sees the same
+    /// request `rewrite` would. Like cached copies, bodies are only
+    /// compressed for requests [`compress_under`] and [`eligible_when`]
+    /// select, if the filters select their type, and archives never are.
+    ///
+    /// [`compress_under`]: CachedCompressionBuilder::compress_under
+    /// [`eligible_when`]: CachedCompressionBuilder::eligible_when
+    pub fn respond(&self, body: impl Into<Vec<u8>>, content_type: ContentType) -> Compressed {
+        Compressed {
+            cache: self.clone(),
+            body: body.into(),
+            compressible: !is_archive_type(&content_type)
+                && self.config.compressible_type(&content_type),
+            content_type,
+        }
+    }
+
+    /// Respond with the file at `path`, negotiated and cached exactly like a
+    /// file the `FileServer` serves. Meant for files served outside of it,
+    /// most usefully error pages: `FileServer` forwards requests for missing
+    /// files rather than rewriting them, so a catcher has to serve the page:
+    ///
+    /// ```rust,no_run
+    /// # use rocket::{catch, Request};
+    /// # use rocket_caching_layer::{CachedCompression, CachedFile};
+    /// #[catch(404)]
+    /// async fn not_found(req: &Request<'_>) -> Option<CachedFile> {
+    ///     let cache = req.rocket().state::<CachedCompression>()?;
+    ///     cache.file("static/404.html", req).await.ok()
+    /// }
+    /// ```
+    ///
+    /// The catcher's status is kept, and the page is compressed in the
+    /// background after it is first served, as usual.
+    pub async fn file(
         &self,
-        path: Option<Rewrite<'h>>,
+        path: impl Into<PathBuf>,
         req: &rocket::Request<'_>,
-    ) -> Option<Rewrite<'h>> {
-        match path {
-            Some(Rewrite::File(mut file)) => {
-                if let Some(algo) = self.get_valid(req) {
-                    if self
-                        .map
-                        .get(file.path.as_ref())
-                        .is_some_and(|info| info.compressions.contains(&algo))
-                    {
-                        // Since we change the path, it seems like we override any
-                        // automatic content-type detection, so we just do it manually
-                        // We could implement this directly on File as well
-                        if let Some(ct) = content_type_from_path(&file.path) {
-                            file.headers.add(ct);
-                        }
-                        file.headers
-                            .add(Header::new("Content-Encoding", algo.to_string()));
-                        let new_name = format!(
-                            "{}.{algo}",
-                            file.path.file_name().and_then(|s| s.to_str()).unwrap_or("")
-                        );
-                        file.path.to_mut().set_file_name(new_name);
-                    } else {
-                        self.dispatch(algo, file.path.clone().into_owned());
-                    }
+    ) -> io::Result<CachedFile> {
+        let file = File::new(path.into());
+        let (path, headers) = match self.rewrite(Some(Rewrite::File(file)), req) {
+            Some(Rewrite::File(file)) => (file.path.into_owned(), file.headers),
+            _ => unreachable!("files are always rewritten to files"),
+        };
+        Ok(CachedFile {
+            file: rocket::fs::NamedFile::open(path).await?,
+            headers,
+        })
+    }
+
+    /// Respond with the file at `path`, compressed on the fly as it's sent if
+    /// the request accepts a supported encoding. Nothing is cached or written
+    /// to disk, so this suits large files that are rarely requested:
+    ///
+    /// ```rust,no_run
+    /// # use rocket::{get, State};
+    /// # use rocket_caching_layer::{CachedCompression, Streamed};
+    /// #[get("/exports/latest")]
+    /// async fn export(cache: &State<CachedCompression>) -> std::io::Result<Streamed> {
+    ///     cache.stream_without_cache("exports/latest.csv").await
+    /// }
+    /// ```
+    ///
+    /// Files are compressed at the level they would be cached at, or at the
+    /// initial level of a [`recompress_tier`], since every request compresses
+    /// them again. Like cached copies, they're only compressed for requests
+    /// [`compress_under`] and [`eligible_when`] select, if the filters select
+    /// them. Their compressed length isn't known until the end, so they're
+    /// sent without a `Content-Length`, with chunked transfer encoding over
+    /// HTTP/1.1. Files sent as-is keep their length.
+    ///
+    /// [`recompress_tier`]: CachedCompressionBuilder::recompress_tier
+    /// [`compress_under`]: CachedCompressionBuilder::compress_under
+    /// [`eligible_when`]: CachedCompressionBuilder::eligible_when
+    pub async fn stream_without_cache(&self, path: impl AsRef<Path>) -> io::Result<Streamed> {
+        let path = path.as_ref();
+        let file = rocket::tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let by_size = self.config.level_by_size.as_ref().map(|f| f(size).min(9));
+        let initial = self.config.tier.map(|(initial, _)| initial);
+        let picked = self.overrides(path, true).level.or(by_size).or(initial);
+        Ok(Streamed {
+            cache: self.clone(),
+            file,
+            content_type: content_type_from_path(path),
+            compressible: archive_content_type(path).is_none() && self.config.compressible(path),
+            level: picked.unwrap_or(Self::DEFAULT_LEVEL),
+        })
+    }
+
+    /// Describes why the rewriter can't work as configured, if it can't: the
+    /// `temp_dir()` must be a writable directory, or this fails with
+    /// [`Error::Io`], and the manifest valid.
+    async fn check(&self) -> Result<(), Error> {
+        if let Some(dir) = &self.config.temp_dir {
+            let shown = dir.display();
+            let fail = |kind, e: String| Err(Error::Io(io::Error::new(kind, e)));
+            match rocket::tokio::fs::metadata(dir).await {
+                Ok(meta) if meta.is_dir() => (),
+                Ok(_) => {
+                    let e = format!("temp_dir() {shown} is not a directory");
+                    return fail(io::ErrorKind::NotADirectory, e);
                 }
-                Some(Rewrite::File(file))
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return fail(e.kind(), format!("temp_dir() {shown} does not exist"));
+                }
+                Err(e) => return fail(e.kind(), format!("failed to read temp_dir() {shown}: {e}")),
             }
-            path => path,
+            let probe = temp_path(Path::new("probe"), Some(dir));
+            if let Err(e) = rocket::tokio::fs::write(&probe, b"").await {
+                return fail(e.kind(), format!("temp_dir() {shown} is not writable: {e}"));
+            }
+            let _ = rocket::tokio::fs::remove_file(&probe).await;
+        }
+        if let Some(manifest) = &self.config.manifest {
+            manifest.refresh().map_err(Error::Invalid)?;
         }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::time::Duration;
+    fn bypassed(&self, req: &rocket::Request<'_>) -> bool {
+        self.config.bypass.as_ref().is_some_and(|f| f(req))
+    }
 
-    use rocket::{
-        async_test, build,
-        fs::{rewrite::DirIndex, FileServer},
-        http::Status,
-        local::asynchronous::Client,
-        tokio::time::sleep,
-        Build, Rocket,
-    };
+    /// Whether files requested at the path of `req` may be compressed, as
+    /// decided by `compress_under()` and `eligible_when()`.
+    fn eligible(&self, req: &rocket::Request<'_>) -> bool {
+        let path = req.uri().path().as_str();
+        self.config.under_mount(path) && self.config.eligible.as_ref().is_none_or(|f| f(path))
+    }
 
-    use super::*;
+    /// Whether a compressed copy of `path` is available for `algo`, and
+    /// whether it was generated from the file currently at `path`.
+    fn cached(&self, path: &Path, algo: Algorithm) -> Cached {
+        match self.cached_here(path, algo) {
+            Cached::Missing if self.config.serve_only && self.pregenerated(path, algo) => {
+                Cached::Fresh
+            }
+            cached => cached,
+        }
+    }
 
-    fn launch() -> Rocket<Build> {
-        build().mount(
-            "/",
-            FileServer::without_index("static")
-                .rewrite(DirIndex::unconditional("index.txt"))
-                .rewrite(CachedCompression::new()),
-        )
+    /// Whether a copy of `path` for `algo` was generated ahead of time, for
+    /// `serve_only()`, no earlier than the source was last modified.
+    fn pregenerated(&self, path: &Path, algo: Algorithm) -> bool {
+        let artifact = artifact_path(path, algo, self.config.version());
+        let Ok(local) = self.config.store().local_path(&artifact) else {
+            return false;
+        };
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        modified(&local) >= modified(path)
     }
 
-    async fn gzipped_req(
-        client: &mut Client,
-        accept: impl Into<Option<&'static str>>,
-        compressed: bool,
-    ) {
-        let res = client.get("/");
-        let res = match accept.into() {
-            Some(accept) => res.header(Header::new("Accept-Encoding", accept)),
-            None => res,
+    /// Like `cached()`, for the copies generated by this instance.
+    fn cached_here(&self, path: &Path, algo: Algorithm) -> Cached {
+        let Some(info) = self.map.get(path) else {
+            return Cached::Missing;
+        };
+        // Without touching the file system with `in_memory_hits()`, and
+        // otherwise with a single `stat` shared by both checks
+        let (track_inode, append_mode) = (self.config.track_inode, self.config.append_mode);
+        let in_memory = self.config.revalidate.is_some();
+        let seen = match info.seen {
+            _ if !track_inode && !append_mode => None,
+            seen if in_memory => seen,
+            _ => observe(path, track_inode),
+        };
+        let id = match seen {
+            Some(seen) => seen.id,
+            None if in_memory => info.inode,
+            None => None,
+        };
+        let replaced = track_inode && info.inode != id;
+        let resized = |algo| {
+            append_mode && seen.is_some_and(|s| Some(s.len) != recorded_original(&info, algo))
+        };
+        if info.skip || info.marginal.contains(&algo) {
+            // Whatever replaced it may well be worth compressing
+            if replaced {
+                Cached::Missing
+            } else {
+                Cached::Skipped
+            }
+        } else if !info.compressions.contains(&algo) {
+            Cached::Missing
+        } else if replaced || resized(algo) {
+            Cached::Stale
+        } else {
+            Cached::Fresh
+        }
+    }
+
+    /// Decide how to respond to a request for the file at `path`, given the
+    /// value of its `Accept-Encoding` header.
+    ///
+    /// This is the negotiation `rewrite` performs, without dispatching any work.
+    pub fn decide(&self, accept_encoding: &str, path: &Path) -> Decision {
+        self.negotiate(std::iter::once(accept_encoding), path, false)
+    }
+
+    /// Resolve which file `rewrite` would answer `req` for the file at
+    /// `original_path` with, without dispatching any work, counting a hit or
+    /// miss, or keeping anything read on the way, like a file's policy.
+    /// Whatever isn't cached yet resolves to the original, even
+    /// with `warm_on_first_hit_blocking()`, since nothing is compressed here.
+    pub fn resolve(&self, req: &rocket::Request<'_>, original_path: &Path) -> ResolvedTarget {
+        let excluded = self.bypassed(req) || !self.eligible(req);
+        if excluded || original_path.file_name().is_none() {
+            return ResolvedTarget::Identity;
+        }
+        if archive_content_type(original_path).is_some() {
+            return ResolvedTarget::Identity;
+        }
+        if !self
+            .config
+            .serve_compressed_if
+            .as_ref()
+            .is_none_or(|f| f(req))
+        {
+            return ResolvedTarget::Identity;
+        }
+        let accept = req.headers().get("Accept-Encoding");
+        let algo = match self.negotiate(accept, original_path, false) {
+            Decision::ServeCompressed(algo) | Decision::ServeStale(algo) => algo,
+            Decision::Fallback { serve, .. } => serve,
+            Decision::Dispatch(_) | Decision::ServeIdentity => return ResolvedTarget::Identity,
+        };
+        let artifact = artifact_path(original_path, algo, self.config.version());
+        match self.config.store().local_path(&artifact) {
+            Ok(local) => ResolvedTarget::Compressed {
+                algo,
+                artifact: local.into_owned(),
+            },
+            Err(_) => ResolvedTarget::Identity,
+        }
+    }
+
+    /// What's known about the file at `path`, if it's only checked in the
+    /// background, with `in_memory_hits()`.
+    fn in_memory(&self, path: &Path) -> Option<dashmap::mapref::one::Ref<'_, PathBuf, Info>> {
+        self.config.revalidate.and_then(|_| self.map.get(path))
+    }
+
+    /// The algorithm and level overrides for the file at `path`. Its policy is
+    /// only read again once it changed, and for hits with `in_memory_hits()`,
+    /// only in the background. What's read is only kept if `remember`.
+    fn overrides(&self, path: &Path, remember: bool) -> Overrides {
+        let cached = self.in_memory(path).and_then(|info| info.policy);
+        let policy = match cached {
+            Some((_, policy)) => policy,
+            None => policy_of(&self.map, &self.config, path, remember),
+        };
+        self.config.overrides_with(path, policy)
+    }
+
+    /// Decide how to respond to a request for the file at `path`, keeping
+    /// what's learned about it on the way if `remember`, and otherwise
+    /// changing nothing.
+    fn negotiate<'a>(
+        &self,
+        accept: impl Iterator<Item = &'a str>,
+        path: &Path,
+        remember: bool,
+    ) -> Decision {
+        let weighted = self.weighted(accept);
+        let mut acceptable: Vec<_> = weighted.iter().map(|(algo, _)| *algo).collect();
+        let preferred = self.preferred_by_size(path);
+        // Only ever reordered among the encodings the client weighs equally
+        let mut start = 0;
+        for group in weighted.chunk_by(|(_, a), (_, b)| a == b) {
+            let algos = &mut acceptable[start..start + group.len()];
+            start += group.len();
+            if self.config.adaptive {
+                self.rank(path, algos);
+            }
+            if let Some(i) = algos.iter().position(|algo| Some(*algo) == preferred) {
+                algos[..=i].rotate_right(1);
+            }
+        }
+        if let Some(only) = self.overrides(path, remember).algorithm {
+            acceptable.retain(|algo| *algo == only);
+        }
+        if let Some(info) = self.map.get(path).filter(|info| info.fallback.is_some()) {
+            acceptable.retain(|algo| !info.failed.contains(algo));
+        }
+        if acceptable.is_empty() {
+            return Decision::ServeIdentity;
+        }
+        if archive_content_type(path).is_some() {
+            // Compressing it again would only make it bigger
+            return Decision::ServeIdentity;
+        }
+        if !self.config.compressible(path) {
+            return Decision::ServeIdentity;
+        }
+        // An algorithm that isn't worth it leaves the next one to try
+        let mut acceptable = acceptable
+            .into_iter()
+            .map(|algo| (algo, self.cached(path, algo)))
+            .filter(|(_, cached)| !matches!(cached, Cached::Skipped));
+        let Some((algo, cached)) = acceptable.next() else {
+            return Decision::ServeIdentity;
+        };
+        match cached {
+            Cached::Fresh => Decision::ServeCompressed(algo),
+            Cached::Stale if self.config.stale_while_revalidate => Decision::ServeStale(algo),
+            Cached::Stale | Cached::Missing | Cached::Skipped => {
+                // Rather than the original, send any other encoding the client
+                // accepts that is already cached
+                match acceptable.find(|(_, cached)| matches!(cached, Cached::Fresh)) {
+                    Some((fallback, _)) => Decision::Fallback {
+                        serve: fallback,
+                        dispatch: algo,
+                    },
+                    None => Decision::Dispatch(algo),
+                }
+            }
+        }
+    }
+
+    /// The algorithm `size_based_algorithm()` prefers for the file at `path`,
+    /// if set.
+    fn preferred_by_size(&self, path: &Path) -> Option<Algorithm> {
+        let (threshold, small, large) = self.config.size_based?;
+        let seen = self.in_memory(path).and_then(|info| info.seen);
+        // Errors are left for the `FileServer` to report
+        let len = match seen {
+            Some(seen) => seen.len,
+            None => std::fs::metadata(path).ok()?.len(),
+        };
+        Some(if len < threshold { small } else { large })
+    }
+
+    /// Order `algos` by the average ratio each achieved on other files of the
+    /// same type, best first. Those without enough samples yet come before
+    /// the rest, in the order given, so each gets to compress a few.
+    fn rank(&self, path: &Path, algos: &mut [Algorithm]) {
+        let Some(ext) = extension_key(path) else {
+            return;
+        };
+        let mut ranked: Vec<_> = algos
+            .iter()
+            .map(|algo| (self.stats.mean_ratio(&ext, *algo), *algo))
+            .collect();
+        // Stable, so ties keep their order
+        ranked.sort_by(|(a, _), (b, _)| match (a, b) {
+            (Some(a), Some(b)) => a.total_cmp(b),
+            _ => a.is_some().cmp(&b.is_some()),
+        });
+        for (slot, (_, algo)) in algos.iter_mut().zip(ranked) {
+            *slot = algo;
+        }
+    }
+
+    fn get_valid<'a>(&self, accept: impl Iterator<Item = &'a str>) -> Option<Algorithm> {
+        self.acceptable(accept).next()
+    }
+
+    /// The supported encodings accepted by the client, most preferred first.
+    fn acceptable<'a>(
+        &'a self,
+        accept: impl Iterator<Item = &'a str> + 'a,
+    ) -> impl Iterator<Item = Algorithm> + 'a {
+        self.weighted(accept).into_iter().map(|(algo, _)| algo)
+    }
+
+    /// The supported encodings accepted by the client, with their quality,
+    /// most preferred first.
+    ///
+    /// Only offered encodings are included, ordered by quality, highest first.
+    /// Encodings of equal quality are ordered by the preference if one is set,
+    /// or keep the order the client listed them in otherwise. A `*` accepts
+    /// every offered encoding the client doesn't list, at its quality.
+    ///
+    /// A coding listed more than once is refused if any of its entries has a
+    /// quality of 0, wherever it appears, so `gzip, gzip;q=0` and
+    /// `gzip;q=0, gzip` both exclude gzip. Otherwise, its highest quality
+    /// counts.
+    fn weighted<'a>(&self, accept: impl Iterator<Item = &'a str>) -> Vec<(Algorithm, f32)> {
+        let codings: Vec<_> = accept.flat_map(parse_codings).take(MAX_CODINGS).collect();
+        // By algorithm, so `gzip` and `x-gzip` are the same coding
+        let is = |c: &str, algo: Algorithm| Algorithm::from_name(c) == Some(algo);
+        let listed = |algo: Algorithm| codings.iter().any(|(c, _)| is(c, algo));
+        let refused = |algo: Algorithm| codings.iter().any(|(c, q)| is(c, algo) && *q == 0.);
+        // Precomputed on build, rather than searched for each coding
+        let offered = self.config.offered;
+        let mut accepted: Vec<_> = codings
+            .iter()
+            .filter_map(|(c, q)| Some((Algorithm::from_name(c)?, *q)))
+            .filter(|(algo, q)| *q > 0. && !refused(*algo) && offered.enabled[*algo as usize])
+            .collect();
+        if let Some((_, q)) = codings.iter().find(|(c, q)| *c == "*" && *q > 0.) {
+            let unlisted = self.config.algorithms().iter().filter(|a| !listed(**a));
+            accepted.extend(unlisted.map(|a| (*a, *q)));
+        }
+        // Stable, so ties keep their order without a preference
+        let rank = |algo: &Algorithm| offered.rank[*algo as usize];
+        accepted.sort_by(|(a, qa), (b, qb)| qb.total_cmp(qa).then_with(|| rank(a).cmp(&rank(b))));
+        let mut seen = [false; Algorithm::ALL.len()];
+        accepted.retain(|(algo, _)| !std::mem::replace(&mut seen[*algo as usize], true));
+        accepted
+    }
+
+    /// Start compressing `path` with `algo` for a request, unless only
+    /// pregenerated copies are served, with `serve_only()`.
+    fn dispatch(&self, algo: Algorithm, path: PathBuf) {
+        if self.config.serve_only {
+            return;
+        }
+        // Left to run in the background
+        let _ = self.start(algo, path);
+    }
+
+    /// Like `dispatch()`, returning the task compressing `path` if one was
+    /// started right away, rather than skipped or deferred.
+    fn start(&self, algo: Algorithm, path: PathBuf) -> Option<JoinHandle<()>> {
+        if !self.config.within_root(&path) {
+            // Most likely from a rewriter ordered after this one
+            let shown = path.display();
+            warn!(target: TARGET, "Not compressing {shown}, it is outside the source root");
+            return None;
+        }
+        let scheduled = self.stats.scheduled.load(Ordering::Relaxed);
+        if self.config.max_pending.is_some_and(|max| scheduled >= max) {
+            let shown = path.display();
+            debug!(target: TARGET, scheduled, "Not compressing {shown}, too many are pending");
+            return None;
+        }
+        match &self.config.window {
+            Some(window) if !window.contains(self.config.now()) => {
+                self.defer(algo, path);
+                None
+            }
+            _ => Some(self.spawn(algo, path, Ticket::new(&self.stats))),
+        }
+    }
+
+    /// Count a request for the file at `path` without a copy to serve,
+    /// returning whether it was requested often enough to compress, as set
+    /// by `min_requests()`.
+    fn requested(&self, path: &Path) -> bool {
+        let Some(min) = self.config.min_requests else {
+            return true;
+        };
+        let mut info = self.map.entry(path.to_path_buf()).or_default();
+        info.requests = info.requests.saturating_add(1);
+        info.requests >= min
+    }
+
+    /// Start compressing `path` with `algo`, counted by `ticket` until done.
+    fn spawn(&self, algo: Algorithm, path: PathBuf, ticket: Ticket) -> JoinHandle<()> {
+        let task = self.compression(algo, path);
+        rocket::tokio::spawn(async move {
+            let _ticket = ticket;
+            task.await
+        })
+    }
+
+    /// Queue compressing `path` with `algo` until the compression window
+    /// opens, starting the task that waits for it if needed.
+    fn defer(&self, algo: Algorithm, path: PathBuf) {
+        let mut deferred = self.deferred.lock().unwrap();
+        if !deferred
+            .jobs
+            .iter()
+            .any(|(a, p, _)| *a == algo && *p == path)
+        {
+            deferred.jobs.push((algo, path, Ticket::new(&self.stats)));
+        }
+        if !deferred.scheduled {
+            deferred.scheduled = true;
+            rocket::tokio::spawn(self.clone().run_deferred());
+        }
+    }
+
+    /// Start the task checking cached copies and their sources every `every`,
+    /// for `in_memory_hits()`, unless it's already running. It stops once
+    /// every clone is dropped.
+    fn revalidate_every(&self, every: Duration) {
+        if self.revalidating.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let (map, config) = (Arc::downgrade(&self.map), self.config.clone());
+        rocket::tokio::spawn(async move {
+            loop {
+                rocket::tokio::time::sleep(every).await;
+                let Some(map) = map.upgrade() else {
+                    return;
+                };
+                revalidate(&map, &config).await;
+            }
+        });
+    }
+
+    /// Wait for the compression window to open, then compress everything
+    /// queued, until nothing is left.
+    async fn run_deferred(self) {
+        let Some(window) = self.config.window else {
+            return;
+        };
+        loop {
+            // Checked regularly, rather than sleeping until the window opens,
+            // in case the clock jumps
+            let wait = window.until_open(self.config.now());
+            if !wait.is_zero() {
+                rocket::tokio::time::sleep(wait.min(WINDOW_POLL)).await;
+                continue;
+            }
+            let jobs = {
+                let mut deferred = self.deferred.lock().unwrap();
+                if deferred.jobs.is_empty() {
+                    deferred.scheduled = false;
+                    return;
+                }
+                std::mem::take(&mut deferred.jobs)
+            };
+            debug!(target: TARGET, count = jobs.len(), "Compression window open, compressing");
+            for (algo, path, ticket) in jobs {
+                self.spawn(algo, path, ticket);
+            }
+        }
+    }
+
+    /// Queue recompressing `path` with `algo` at `level` once nothing else is
+    /// being compressed, for `recompress_tier()`, starting the task that waits
+    /// for it if needed.
+    fn queue_upgrade(&self, algo: Algorithm, path: PathBuf, level: u32) {
+        let mut deferred = self.deferred.lock().unwrap();
+        if !deferred
+            .upgrades
+            .iter()
+            .any(|(a, p, _)| *a == algo && *p == path)
+        {
+            deferred.upgrades.push((algo, path, level));
+        }
+        if !deferred.upgrading {
+            deferred.upgrading = true;
+            rocket::tokio::spawn(self.clone().run_upgrades());
+        }
+    }
+
+    /// Run the queued upgrades one at a time, each once no other compression
+    /// is scheduled and the compression window, if any, is open, until none
+    /// are left.
+    async fn run_upgrades(self) {
+        loop {
+            let idle = self.stats.scheduled.load(Ordering::Acquire) == 0;
+            let open = self
+                .config
+                .window
+                .is_none_or(|w| w.contains(self.config.now()));
+            if !idle || !open {
+                rocket::tokio::time::sleep(IDLE_POLL).await;
+                continue;
+            }
+            let (algo, path, level) = {
+                let mut deferred = self.deferred.lock().unwrap();
+                if deferred.upgrades.is_empty() {
+                    deferred.upgrading = false;
+                    return;
+                }
+                deferred.upgrades.remove(0)
+            };
+            debug!(target: TARGET, "Recompressing {} at level {level}", path.display());
+            let _ticket = Ticket::new(&self.stats);
+            self.compression_at(algo, path, Some(level)).await;
+        }
+    }
+
+    /// The task compressing `path` with `algo`, which does nothing if the same
+    /// compression is already pending.
+    fn compression(&self, algo: Algorithm, path: PathBuf) -> impl Future<Output = ()> + 'static {
+        self.compression_at(algo, path, None)
+    }
+
+    /// Like `compression()`, at level `at` if set, replacing any other level
+    /// the options pick, and the whole file, even with `append_mode()`.
+    fn compression_at(
+        &self,
+        algo: Algorithm,
+        path: PathBuf,
+        at: Option<u32>,
+    ) -> impl Future<Output = ()> + 'static {
+        let this = self.clone();
+        let map = self.map.clone();
+        let config = self.config.clone();
+        let stats = self.stats.clone();
+        async move {
+            let pending = map.entry(path.clone()).or_default().pending.clone();
+            if !pending.claim(algo) {
+                return;
+            }
+            // However this task ends, even by panicking, later requests can retry
+            let _pending = PendingGuard { pending, algo };
+            if let Some(Err(e)) = config.manifest.as_ref().map(|m| m.refresh()) {
+                warn!(target: TARGET, %e, "Keeping the previous compression manifest");
+            }
+            // Errors are left for the compression to report
+            let meta = rocket::tokio::fs::metadata(&path).await;
+            let by_size = config.level_by_size.as_ref().map(|f| {
+                let size = meta.as_ref().map_or(0, |meta| meta.len());
+                f(size).min(9)
+            });
+            let policy = policy_of(&map, &config, &path, true);
+            let picked = at
+                .or(config.overrides_with(&path, policy).level)
+                .or(by_size);
+            let (level, upgrade) = match (picked, config.tier) {
+                (Some(level), _) => (level, None),
+                (None, Some((initial, final_level))) => (initial, Some(final_level)),
+                (None, None) => (Self::DEFAULT_LEVEL, None),
+            };
+            if let (Some(min_age), Ok(meta)) = (config.min_age, &meta) {
+                let settled = meta
+                    .modified()
+                    .is_ok_and(|t| t.elapsed().is_ok_and(|age| age >= min_age));
+                if !settled {
+                    let shown = path.display();
+                    debug!(target: TARGET, "Not compressing {shown} yet, it was modified recently");
+                    return;
+                }
+            }
+            if meta.as_ref().is_ok_and(|meta| meta.len() == 0) {
+                let mut v = map.entry(path.clone()).or_default();
+                v.compressions.clear();
+                v.inode = if config.track_inode {
+                    file_id(&path)
+                } else {
+                    None
+                };
+                v.skip = true;
+                drop(v);
+                debug!(target: TARGET, "Not compressing {}, it is empty", path.display());
+                return;
+            }
+            let new_path = artifact_path(&path, algo, config.version());
+            // Captured before compressing, so a swap during compression leaves
+            // the entry stale rather than mislabelled.
+            let inode = if config.track_inode {
+                file_id(&path)
+            } else {
+                None
+            };
+            let header = match (&meta, algo) {
+                (Ok(meta), Algorithm::Gzip) if config.gzip_embed_metadata => {
+                    Some(gzip_header(&path, meta))
+                }
+                // Written by flate2 rather than zlib, which records the OS it
+                // runs on, with a fixed one, so copies are byte-identical
+                // wherever they're built
+                (_, Algorithm::Gzip) => Some(GzipHeader::default()),
+                _ => None,
+            };
+            let encoder = |level| match (&header, config.parallel) {
+                (header, Some(threads)) if algo == Algorithm::Gzip => {
+                    Encoder::ParallelGzip(header.clone(), Compression::new(level), threads)
+                }
+                (Some(header), _) => Encoder::Gzip(header.clone(), Compression::new(level)),
+                (None, _) => Encoder::Stream(algo.compressor(level)),
+            };
+
+            let (attempts, backoff) = config.retry.unwrap_or(DEFAULT_RETRY);
+            let (encoder, src, dst, cfg) = (&encoder, &path, &new_path, &*config);
+            let compress = move |level| {
+                retry(attempts, backoff, move || {
+                    Self::compress(encoder(level), src, dst, cfg)
+                })
+            };
+
+            let original = meta.as_ref().map_or(0, |meta| meta.len());
+            let mtime = meta
+                .as_ref()
+                .ok()
+                .and_then(|meta| meta.modified().ok())
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs());
+            let describe = |level, compressed| ArtifactMeta {
+                algo,
+                level,
+                original,
+                compressed,
+                mtime,
+            };
+            // Only the new bytes of a file that grew are compressed, if those
+            // before them are what the copy holds
+            let appended_from = (config.append_mode && algo == Algorithm::Gzip && at.is_none())
+                .then(|| map.get(&path))
+                .flatten()
+                .filter(|v| v.inode == inode && v.compressions.contains(&algo))
+                .and_then(|v| recorded_original(&v, algo))
+                .filter(|from| (1..original).contains(from));
+            let res = match appended_from {
+                Some(from) => {
+                    let range = from..original;
+                    retry(attempts, backoff, || {
+                        Self::append_gzip(range.clone(), level, &path, &new_path, &config)
+                    })
+                    .await
+                }
+                None => compress(level).await,
+            };
+            let compressed = match res {
+                Ok(size) => {
+                    stats.compressions.fetch_add(1, Ordering::Relaxed);
+                    debug!(target: TARGET, "Compressed {} with {algo}", path.display());
+                    if config.adaptive {
+                        stats.learn(&path, algo, original, size);
+                    }
+                    Some(size)
+                }
+                Err(e) => {
+                    stats.failures.fetch_add(1, Ordering::Relaxed);
+                    error!(target: TARGET, ?e, "Error when compressing file {}", path.display());
+                    if let Some(next) = config.fallback_after(algo) {
+                        debug!(target: TARGET, "Falling back to {next} for {}", path.display());
+                        let mut v = map.entry(path.clone()).or_default();
+                        if !v.failed.contains(&algo) {
+                            v.failed.push(algo);
+                        }
+                        drop(v);
+                        this.dispatch(next, path.clone());
+                    }
+                    None
+                }
+            };
+            let marginal = compressed
+                .zip(config.min_savings_ratio)
+                .is_some_and(|(size, min)| 1. - size as f64 / (original.max(1) as f64) < min);
+            if marginal {
+                match config.store().remove(&new_path).await {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        let shown = new_path.display();
+                        warn!(target: TARGET, ?e, "Failed to remove cached copy {shown}");
+                    }
+                    _ => (),
+                }
+                if config.write_metadata {
+                    Self::remove_metadata(&new_path, &config).await;
+                }
+                let mut v = map.entry(path.clone()).or_default();
+                if v.inode != inode {
+                    v.marginal.clear();
+                }
+                v.compressions.retain(|a| *a != algo);
+                v.inode = inode;
+                if !v.marginal.contains(&algo) {
+                    v.marginal.push(algo);
+                }
+                drop(v);
+                let shown = path.display();
+                debug!(target: TARGET, "Not compressing {shown} with {algo}, it barely shrinks");
+                return;
+            }
+            {
+                let mut v = map.entry(path.clone()).or_default();
+                if let Some(size) = compressed {
+                    if v.inode != inode {
+                        // The source was replaced, every other copy is stale
+                        v.compressions.clear();
+                        v.failed.clear();
+                        v.fallback = None;
+                        v.served_from.clear();
+                        v.marginal.clear();
+                        v.inode = inode;
+                    }
+                    v.failed.retain(|a| *a != algo);
+                    v.marginal.retain(|a| *a != algo);
+                    v.fallback = (!v.failed.is_empty()).then_some(algo);
+                    v.skip = false;
+                    if !v.compressions.contains(&algo) {
+                        v.compressions.push(algo);
+                    }
+                    v.record_sizes(algo, original, size);
+                    if config.revalidate.is_some() {
+                        v.seen = Some(Seen {
+                            id: inode,
+                            len: original,
+                        });
+                    }
+                }
+                drop(v);
+            }
+            if let (Some(_), Some(every)) = (compressed, config.revalidate) {
+                remember_copy(&map, &config, &path, algo);
+                this.revalidate_every(every);
+            }
+            if let (Some(size), true) = (compressed, config.write_metadata) {
+                Self::write_metadata(&describe(level, size), &new_path, &config).await;
+            }
+            // The quick copy is served until the upgrade replaces it
+            if let (Some(_), Some(level)) = (compressed, upgrade) {
+                this.queue_upgrade(algo, path.clone(), level);
+            }
+        }
+    }
+
+    /// Compress `path` into the copy stored as `new_path`, returning its size.
+    /// The output is written to a temporary file and handed to the store once
+    /// complete, so an existing copy stays servable until it is replaced.
+    async fn compress(
+        encoder: Encoder,
+        path: &Path,
+        new_path: &Path,
+        config: &Config,
+    ) -> io::Result<u64> {
+        let tmp = temp_path(new_path, config.temp_dir.as_deref());
+        let mode = config.artifact_mode;
+        let chunking = Chunking::of(config);
+        // Each chunk goes through the hook as it's written
+        let hook = config.post_process.as_ref();
+        let res = match encoder {
+            Encoder::Stream(compressor) => {
+                Self::compress_to(compressor, path, &tmp, mode, hook, chunking).await
+            }
+            Encoder::Gzip(header, level) => {
+                Self::compress_gzip_to(header, level, path, &tmp, mode, hook, chunking).await
+            }
+            Encoder::ParallelGzip(header, level, threads) => {
+                Self::compress_parallel_to(header, level, threads, path, &tmp, config).await
+            }
+        };
+        let res = async {
+            res?;
+            let size = rocket::tokio::fs::metadata(&tmp).await?.len();
+            config.store().put(new_path, &tmp).await?;
+            Ok::<_, io::Error>(size)
         }
-        .dispatch()
         .await;
-        assert_eq!(res.status(), Status::Ok);
+        if res.is_err() {
+            let _ = rocket::tokio::fs::remove_file(&tmp).await;
+        }
+        res
+    }
+
+    /// Compress the bytes of `path` in `range` at `level` into a new gzip
+    /// member, and append it to the gzip copy stored as `new_path`, returning
+    /// the copy's new size. Decoders concatenate the contents of members, so
+    /// the copy decodes to the whole file.
+    async fn append_gzip(
+        range: std::ops::Range<u64>,
+        level: u32,
+        path: &Path,
+        new_path: &Path,
+        config: &Config,
+    ) -> io::Result<u64> {
+        let mut input = rocket::tokio::fs::File::open(path).await?;
+        input.seek(io::SeekFrom::Start(range.start)).await?;
+        let mut delta = input.take(range.end - range.start);
+        let copy = config.store().local_path(new_path)?.into_owned();
+
+        let tmp = temp_path(new_path, config.temp_dir.as_deref());
+        let res = async {
+            create_parent(&tmp).await?;
+            let mut output = rocket::tokio::fs::File::create(&tmp).await?;
+            if let Some(mode) = config.artifact_mode {
+                set_mode(&output, mode).await?;
+            }
+            let mut copy = rocket::tokio::fs::File::open(&copy).await?;
+            rocket::tokio::io::copy(&mut copy, &mut output).await?;
+            // The copy went through the hook when it was written, the new
+            // member goes through it as it's compressed
+            let (header, level) = (GzipHeader::default(), Compression::new(level));
+            let hook = config.post_process.as_ref();
+            let chunking = Chunking::of(config);
+            Self::gzip_stream(header, level, &mut delta, &mut output, hook, chunking).await?;
+            let size = rocket::tokio::fs::metadata(&tmp).await?.len();
+            config.store().put(new_path, &tmp).await?;
+            Ok::<_, io::Error>(size)
+        }
+        .await;
+        if res.is_err() {
+            let _ = rocket::tokio::fs::remove_file(&tmp).await;
+        }
+        res
+    }
+
+    /// Compress the file at `path` with `algo` at `level` into a new file at
+    /// `dest`, through buffers of `buffer_size` bytes, returning its size. The
+    /// output is the same as the copies generated to serve, with the default
+    /// options, but nothing is cached or written anywhere else, e.g. to
+    /// compare levels and buffer sizes:
+    ///
+    /// ```rust,no_run
+    /// # use rocket_caching_layer::{Algorithm, CachedCompression};
+    /// # async fn f() -> std::io::Result<()> {
+    /// for level in [1, 6, 9] {
+    ///     let size = CachedCompression::compress_file(Algorithm::Gzip, level, 8192, "in", "out");
+    ///     println!("level {level}: {} bytes", size.await?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Missing directories `dest` goes in are created. Fails with
+    /// [`io::ErrorKind::InvalidInput`] if `level` is above 9, or
+    /// `buffer_size` is zero.
+    pub async fn compress_file(
+        algo: Algorithm,
+        level: u32,
+        buffer_size: usize,
+        path: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+    ) -> io::Result<u64> {
+        if level > 9 || buffer_size == 0 {
+            let e = "compress_file() needs a level of at most 9, and a buffer";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+        let (path, dest) = (path.as_ref(), dest.as_ref());
+        let chunking = Chunking {
+            buffer: buffer_size,
+            flush: None,
+            yield_every: None,
+        };
+        match algo {
+            Algorithm::Gzip => {
+                let (header, level) = (GzipHeader::default(), Compression::new(level));
+                Self::compress_gzip_to(header, level, path, dest, None, None, chunking).await?
+            }
+            Algorithm::DeflateRaw => {
+                let compressor = algo.compressor(level);
+                Self::compress_to(compressor, path, dest, None, None, chunking).await?
+            }
+        }
+        Ok(rocket::tokio::fs::metadata(dest).await?.len())
+    }
+
+    /// Write `meta` next to the copy stored as `artifact`, replacing it
+    /// atomically like the copy itself. Failures are only logged, since the
+    /// copy is still usable.
+    async fn write_metadata(meta: &ArtifactMeta, artifact: &Path, config: &Config) {
+        let path = metadata_path(artifact);
+        let tmp = temp_path(&path, config.temp_dir.as_deref());
+        let res = async {
+            rocket::tokio::fs::write(&tmp, meta.to_json()).await?;
+            config.store().put(&path, &tmp).await
+        }
+        .await;
+        if let Err(e) = res {
+            let _ = rocket::tokio::fs::remove_file(&tmp).await;
+            warn!(target: TARGET, ?e, "Failed to write metadata {}", path.display());
+        }
+    }
+
+    /// Remove the metadata written next to the copy stored as `artifact`, if
+    /// any.
+    async fn remove_metadata(artifact: &Path, config: &Config) {
+        let path = metadata_path(artifact);
+        match config.store().remove(&path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!(target: TARGET, ?e, "Failed to remove metadata {}", path.display())
+            }
+            _ => (),
+        }
+    }
+
+    /// Compress `path` into a gzip stream, with a custom header, at `new_path`.
+    async fn compress_gzip_to(
+        header: GzipHeader,
+        level: Compression,
+        path: &Path,
+        new_path: &Path,
+        mode: Option<u32>,
+        hook: Option<&PostProcess>,
+        chunking: Chunking,
+    ) -> io::Result<()> {
+        let mut input = rocket::tokio::fs::File::open(path).await?;
+        create_parent(new_path).await?;
+        let mut output = rocket::tokio::fs::File::create(new_path).await?;
+        if let Some(mode) = mode {
+            set_mode(&output, mode).await?;
+        }
+        Self::gzip_stream(header, level, &mut input, &mut output, hook, chunking).await
+    }
+
+    /// Compress everything read from `input` into a gzip stream with a custom
+    /// header, written to `output` in chunks as set by `chunking`, each passed
+    /// through `hook` first if set.
+    async fn gzip_stream(
+        header: GzipHeader,
+        level: Compression,
+        input: &mut (impl AsyncRead + Unpin),
+        output: &mut (impl AsyncWrite + Unpin),
+        hook: Option<&PostProcess>,
+        chunking: Chunking,
+    ) -> io::Result<()> {
+        use std::io::Write;
+
+        // `GzEncoder` only drives sync writers, so it writes into a buffer that
+        // is drained into the output after every chunk.
+        let mut encoder = header.builder().write(Vec::new(), level);
+        let mut input_buf = vec![0u8; chunking.buffer];
+        let (mut unflushed, mut chunks) = (0, 0);
+        loop {
+            let size = input.read(&mut input_buf).await?;
+            if size == 0 {
+                break;
+            }
+            encoder.write_all(&input_buf[..size])?;
+            unflushed += size;
+            if chunking.flush.is_some_and(|interval| unflushed >= interval) {
+                encoder.flush()?;
+                unflushed = 0;
+            }
+            if let Some(hook) = hook.filter(|_| !encoder.get_ref().is_empty()) {
+                hook(encoder.get_mut());
+            }
+            output.write_all(encoder.get_ref()).await?;
+            encoder.get_mut().clear();
+            chunking.pace(&mut chunks).await;
+        }
+        let mut rest = encoder.finish()?;
+        if let Some(hook) = hook {
+            hook(&mut rest);
+        }
+        output.write_all(&rest).await?;
+        output.flush().await
+    }
+
+    /// Compress `path` into a gzip stream at `new_path`, splitting it into
+    /// blocks that are compressed on up to `threads` blocking threads at once.
+    /// Each block becomes a gzip member of its own, and decoders concatenate
+    /// their contents. Only the first member carries `header`. Each member
+    /// goes through the `post_process()` hook as it's written, if set.
+    async fn compress_parallel_to(
+        mut header: Option<GzipHeader>,
+        level: Compression,
+        threads: usize,
+        path: &Path,
+        new_path: &Path,
+        config: &Config,
+    ) -> io::Result<()> {
+        use std::io::Write;
+
+        let flush = config.progressive_flush;
+        let mut input = rocket::tokio::fs::File::open(path).await?;
+        create_parent(new_path).await?;
+        let mut output = rocket::tokio::fs::File::create(new_path).await?;
+        if let Some(mode) = config.artifact_mode {
+            set_mode(&output, mode).await?;
+        }
+        let mut written = false;
+        let mut eof = false;
+        while !eof {
+            let mut members = Vec::with_capacity(threads);
+            while members.len() < threads && !eof {
+                let mut block = Vec::with_capacity(PARALLEL_BLOCK);
+                (&mut input)
+                    .take(PARALLEL_BLOCK as u64)
+                    .read_to_end(&mut block)
+                    .await?;
+                eof = block.len() < PARALLEL_BLOCK;
+                // An empty input still needs one member to be a valid stream
+                if block.is_empty() && (written || !members.is_empty()) {
+                    break;
+                }
+                let builder = header.take().unwrap_or_default().builder();
+                members.push(rocket::tokio::task::spawn_blocking(move || {
+                    let mut encoder = builder.write(Vec::new(), level);
+                    for chunk in block.chunks(flush.unwrap_or(PARALLEL_BLOCK).max(1)) {
+                        encoder.write_all(chunk)?;
+                        if flush.is_some() {
+                            encoder.flush()?;
+                        }
+                    }
+                    encoder.finish()
+                }));
+            }
+            for member in members {
+                let mut member = member.await.map_err(io::Error::other)??;
+                if let Some(hook) = &config.post_process {
+                    hook(&mut member);
+                }
+                output.write_all(&member).await?;
+                written = true;
+            }
+        }
+        output.flush().await
+    }
+
+    async fn compress_to(
+        compressor: Compress,
+        path: &Path,
+        new_path: &Path,
+        mode: Option<u32>,
+        hook: Option<&PostProcess>,
+        chunking: Chunking,
+    ) -> io::Result<()> {
+        let mut input = rocket::tokio::fs::File::open(path).await?;
+        create_parent(new_path).await?;
+        let mut output = rocket::tokio::fs::File::create(new_path).await?;
+        if let Some(mode) = mode {
+            set_mode(&output, mode).await?;
+        }
+        Self::compress_stream(compressor, &mut input, &mut output, hook, chunking).await
+    }
+
+    /// Compress everything read from `input` into `output`, in chunks as set
+    /// by `chunking`, each passed through `hook` first if set.
+    async fn compress_stream(
+        mut compressor: Compress,
+        input: &mut (impl AsyncRead + Unpin),
+        output: &mut (impl AsyncWrite + Unpin),
+        hook: Option<&PostProcess>,
+        chunking: Chunking,
+    ) -> io::Result<()> {
+        // This isn't the ideal API to be using, but flate2 only provides sync APIs, so I have to
+        // deal with the async files for it.
+        let mut input_buf = vec![0u8; chunking.buffer];
+        let mut output_buf = vec![0u8; chunking.buffer];
+        // Where chunks are copied to for the hook, which may resize them
+        let mut hooked = Vec::new();
+        let (mut unflushed, mut chunks) = (0, 0);
+        loop {
+            let size = input.read(&mut input_buf).await?;
+            if size == 0 {
+                loop {
+                    let (out_size, done) = finish_step(&mut compressor, &mut output_buf)?;
+                    write_chunk(output, &output_buf[..out_size], hook, &mut hooked).await?;
+                    if done {
+                        break;
+                    }
+                }
+                break;
+            }
+            let mut rem = &input_buf[..size];
+            while rem.len() > 0 {
+                let start_in = compressor.total_in();
+                let start_out = compressor.total_out();
+                match compressor.compress(rem, &mut output_buf, flate2::FlushCompress::None) {
+                    Ok(Status::Ok) => {
+                        let in_size = compressor.total_in() - start_in;
+                        let out_size = compressor.total_out() - start_out;
+
+                        let out = &output_buf[..out_size as usize];
+                        write_chunk(output, out, hook, &mut hooked).await?;
+                        rem = &rem[in_size as usize..];
+                    }
+                    Ok(Status::BufError) => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, ""))
+                    }
+                    Ok(Status::StreamEnd) => {
+                        let e = "compressor ended the stream before its input";
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                    }
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "")),
+                }
+            }
+            unflushed += size;
+            if chunking.flush.is_some_and(|interval| unflushed >= interval) {
+                unflushed = 0;
+                loop {
+                    let start_out = compressor.total_out();
+                    compressor
+                        .compress(&[], &mut output_buf, FlushCompress::Sync)
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, ""))?;
+                    let out_size = (compressor.total_out() - start_out) as usize;
+                    write_chunk(output, &output_buf[..out_size], hook, &mut hooked).await?;
+                    // A full buffer may have cut the flush short
+                    if out_size < output_buf.len() {
+                        break;
+                    }
+                }
+            }
+            chunking.pace(&mut chunks).await;
+        }
+        // Note: this will only be executed if the above succeeds.
+        output.flush().await
+    }
+}
+
+/// A file served with its cached compressed copy, if any, created by
+/// [`CachedCompression::file`].
+pub struct CachedFile {
+    file: rocket::fs::NamedFile,
+    headers: HeaderMap<'static>,
+}
+
+impl<'r> Responder<'r, 'static> for CachedFile {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> response::Result<'static> {
+        let mut res = self.file.respond_to(req)?;
+        // Replaces the `Content-Type` guessed from a compressed copy's name
+        for header in self.headers.iter() {
+            res.remove_header(header.name().as_str());
+        }
+        for header in self.headers.into_iter() {
+            res.adjoin_header(header);
+        }
+        Ok(res)
+    }
+}
+
+/// A file compressed as it's sent, created by
+/// [`CachedCompression::stream_without_cache`].
+pub struct Streamed {
+    cache: CachedCompression,
+    file: rocket::tokio::fs::File,
+    content_type: Option<ContentType>,
+    compressible: bool,
+    level: u32,
+}
+
+/// How much compressed output is buffered for a `Streamed` response.
+const STREAM_BUFFER: usize = 64 * 1024;
+
+impl<'r> Responder<'r, 'static> for Streamed {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> response::Result<'static> {
+        let allowed = self.compressible
+            && !self.cache.bypassed(req)
+            && self.cache.eligible(req)
+            && self
+                .cache
+                .config
+                .serve_compressed_if
+                .as_ref()
+                .is_none_or(|f| f(req));
+        let algo = if allowed {
+            self.cache.get_valid(req.headers().get("Accept-Encoding"))
+        } else {
+            None
+        };
+        let mut res = Response::build();
+        res.raw_header("Vary", "Accept-Encoding");
+        if let Some(content_type) = self.content_type {
+            res.header(content_type);
+        }
+        let Some(algo) = algo else {
+            return res.sized_body(None, self.file).ok();
+        };
+        let (mut output, body) = rocket::tokio::io::duplex(STREAM_BUFFER);
+        let (mut input, cache) = (self.file, self.cache);
+        let compressor = algo.compressor(self.level);
+        rocket::tokio::spawn(async move {
+            // Also fails if the client goes away, which needn't be reported.
+            // Not paced, since a request is waiting on it.
+            let chunking = Chunking {
+                yield_every: None,
+                ..Chunking::of(&cache.config)
+            };
+            let sent = CachedCompression::compress_stream(
+                compressor,
+                &mut input,
+                &mut output,
+                None,
+                chunking,
+            );
+            if let Err(e) = sent.await {
+                debug!(target: TARGET, ?e, "Stopped streaming a compressed file");
+            }
+        });
+        // Unsized, so the length of the original is never sent along
+        res.raw_header("Content-Encoding", algo.name())
+            .streamed_body(body)
+            .ok()
+    }
+}
+
+/// A response body compressed in memory, created by
+/// [`CachedCompression::respond`].
+pub struct Compressed {
+    cache: CachedCompression,
+    body: Vec<u8>,
+    content_type: ContentType,
+    compressible: bool,
+}
+
+impl<'r> Responder<'r, 'static> for Compressed {
+    fn respond_to(self, req: &'r rocket::Request<'_>) -> response::Result<'static> {
+        let allowed = self.compressible
+            && !self.cache.bypassed(req)
+            && self.cache.eligible(req)
+            && self
+                .cache
+                .config
+                .serve_compressed_if
+                .as_ref()
+                .is_none_or(|f| f(req));
+        let algo = if allowed && !self.body.is_empty() {
+            self.cache.get_valid(req.headers().get("Accept-Encoding"))
+        } else {
+            None
+        };
+        let mut res = Response::build();
+        res.header(self.content_type)
+            .raw_header("Vary", "Accept-Encoding");
+        let level = CachedCompression::DEFAULT_LEVEL;
+        let body = match algo.map(|algo| (algo, compress_bytes(algo, level, &self.body))) {
+            Some((algo, Ok(compressed))) => {
+                res.raw_header("Content-Encoding", algo.name());
+                compressed
+            }
+            Some((_, Err(e))) => {
+                error!(target: TARGET, ?e, "Error when compressing response body");
+                self.body
+            }
+            None => self.body,
+        };
+        // Sized, so Rocket sends the length of the body as it's served
+        res.sized_body(body.len(), Cursor::new(body)).ok()
+    }
+}
+
+/// Parse the value of an `Accept-Encoding` header into its codings and their
+/// quality values, in the order they're listed. At most 32 codings are
+/// returned, anything past them is ignored.
+///
+/// Codings without a name are skipped. A coding without a `q` parameter has a
+/// quality of 1, and one whose `q` isn't a number between 0 and 1 has a
+/// quality of 0, so it isn't accepted. If `q` is given more than once, the
+/// first one counts. Other parameters are ignored.
+///
+/// ```rust
+/// # use rocket_caching_layer::parse_accept_encoding;
+/// let codings = parse_accept_encoding("gzip;q=0.5, br, identity;q=0");
+/// let names: Vec<_> = codings.iter().map(|(name, _)| name.as_str()).collect();
+/// assert_eq!(names, ["gzip", "br", "identity"]);
+/// assert_eq!(codings[0].1, 0.5);
+/// assert_eq!(codings[2].1, 0.0);
+/// ```
+pub fn parse_accept_encoding(header: &str) -> Vec<(String, f32)> {
+    parse_codings(header)
+        .map(|(name, q)| (name.to_string(), q))
+        .collect()
+}
+
+/// The codings in `header`, as parsed by [`parse_accept_encoding`], borrowed
+/// rather than copied.
+fn parse_codings(header: &str) -> impl Iterator<Item = (&str, f32)> {
+    header.split(',').take(MAX_CODINGS).filter_map(|coding| {
+        let mut parts = coding.split(';');
+        let name = parts.next()?.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let q = parts
+            .filter_map(|p| p.split_once('='))
+            .find(|(p, _)| p.trim().eq_ignore_ascii_case("q"))
+            .map_or(1., |(_, val)| match val.trim().parse::<f32>() {
+                Ok(q) if (0. ..=1.).contains(&q) => q,
+                _ => 0.,
+            });
+        Some((name, q))
+    })
+}
+
+/// Parse a comma-separated list of algorithms by their `Accept-Encoding`
+/// tokens, most preferred first, e.g. `"gzip, deflate-raw"`, for
+/// [`CachedCompressionBuilder::prefer`]. Fails on unknown tokens, and on an
+/// empty list.
+///
+/// ```rust
+/// # use rocket_caching_layer::{parse_preference, Algorithm};
+/// assert_eq!(
+///     parse_preference("deflate-raw, gzip"),
+///     Ok(vec![Algorithm::DeflateRaw, Algorithm::Gzip])
+/// );
+/// assert!(parse_preference("br, gzip").is_err());
+/// ```
+pub fn parse_preference(list: &str) -> Result<Vec<Algorithm>, String> {
+    let order = list
+        .split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            Algorithm::from_name(token).ok_or_else(|| format!("unknown encoding `{token}`"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if order.is_empty() {
+        return Err("the preference needs at least one encoding".into());
+    }
+    Ok(order)
+}
+
+/// Make one call finishing the stream of `compressor` into `output_buf`,
+/// returning how much was written, and whether the stream is complete.
+fn finish_step(compressor: &mut Compress, output_buf: &mut [u8]) -> io::Result<(usize, bool)> {
+    let start_out = compressor.total_out();
+    match compressor.compress(&[], output_buf, FlushCompress::Finish) {
+        Ok(status @ (Status::Ok | Status::StreamEnd)) => {
+            let out_size = (compressor.total_out() - start_out) as usize;
+            let done = status == Status::StreamEnd;
+            if out_size == 0 && !done {
+                // Calling it again would make no more progress, and spin forever
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "no progress finishing the stream",
+                ));
+            }
+            Ok((out_size, done))
+        }
+        Ok(Status::BufError) => Err(io::Error::new(io::ErrorKind::InvalidData, "")),
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "")),
+    }
+}
+
+/// Compress `data` in memory.
+fn compress_bytes(algo: Algorithm, level: u32, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut compressor = algo.compressor(level);
+    let mut out = Vec::with_capacity(data.len() / 2 + 64);
+    loop {
+        let input = &data[compressor.total_in() as usize..];
+        match compressor.compress_vec(input, &mut out, FlushCompress::Finish) {
+            Ok(Status::StreamEnd) => return Ok(out),
+            // Out of room, there's more output to come
+            Ok(Status::Ok | Status::BufError) => out.reserve(out.capacity()),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// Attempts made, and the delay before the first retry, unless configured
+/// with `retry()`.
+const DEFAULT_RETRY: (u32, Duration) = (3, Duration::from_millis(50));
+
+/// Whether `e` is likely to go away if the operation is retried.
+fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        // Out of file descriptors (ENFILE and EMFILE)
+        _ => cfg!(unix) && matches!(e.raw_os_error(), Some(23 | 24)),
+    }
+}
+
+/// Run `op`, retrying transient failures with a delay starting at `backoff`
+/// and doubling each time, for at most `attempts` tries in total.
+async fn retry<F, Fut, T>(attempts: u32, backoff: Duration, mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if attempt < attempts && is_transient(&e) => {
+                warn!(target: TARGET, ?e, ?delay, "Transient error when compressing, retrying");
+                rocket::tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// How often waiting for a pending compression checks whether it finished.
+const PENDING_POLL: Duration = Duration::from_millis(5);
+
+/// Compressions waiting for the compression window to open, and upgrades to
+/// the final level of `recompress_tier()` waiting for idle time.
+#[derive(Default)]
+struct Deferred {
+    jobs: Vec<(Algorithm, PathBuf, Ticket)>,
+    /// Whether a task is waiting to run the `jobs`.
+    scheduled: bool,
+    /// Not counted as scheduled until they run, so they wait for each other.
+    upgrades: Vec<(Algorithm, PathBuf, u32)>,
+    /// Whether a task is waiting to run the `upgrades`.
+    upgrading: bool,
+}
+
+/// How often the task running deferred compressions checks the clock.
+const WINDOW_POLL: Duration = Duration::from_secs(1);
+
+/// How often the task running upgrades checks whether compressions are idle.
+const IDLE_POLL: Duration = Duration::from_millis(50);
+
+/// Counts a compression as scheduled, for `max_pending()` and `shutdown()`,
+/// until dropped.
+struct Ticket(Arc<Stats>);
+
+impl Ticket {
+    fn new(stats: &Arc<Stats>) -> Self {
+        stats.scheduled.fetch_add(1, Ordering::Relaxed);
+        Self(stats.clone())
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.0.scheduled.fetch_sub(1, Ordering::Release) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Clears the pending flag of `algo` for `path` when dropped.
+struct PendingGuard {
+    pending: Arc<Pending>,
+    algo: Algorithm,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.pending.release(self.algo);
+    }
+}
+
+/// The algorithms a file is being compressed with, one bit each.
+#[derive(Default)]
+struct Pending(AtomicU8);
+
+impl Pending {
+    fn bit(algo: Algorithm) -> u8 {
+        1 << algo as u8
+    }
+
+    /// Mark `algo` pending, returning whether it wasn't already, in a single
+    /// step, so only one of several racing compressions runs.
+    fn claim(&self, algo: Algorithm) -> bool {
+        let bit = Self::bit(algo);
+        self.0.fetch_or(bit, Ordering::AcqRel) & bit == 0
+    }
+
+    fn release(&self, algo: Algorithm) {
+        self.0.fetch_and(!Self::bit(algo), Ordering::AcqRel);
+    }
+
+    fn contains(&self, algo: Algorithm) -> bool {
+        self.0.load(Ordering::Acquire) & Self::bit(algo) != 0
+    }
+}
+
+/// How a compressed stream is produced.
+enum Encoder {
+    /// Driven directly, with `Compress` providing any framing.
+    Stream(Compress),
+    /// A gzip stream, with a custom header.
+    Gzip(GzipHeader, Compression),
+    /// A multi-member gzip stream, compressed on the given number of threads.
+    ParallelGzip(Option<GzipHeader>, Compression, usize),
+}
+
+/// How a file is fed through a compressor.
+#[derive(Clone, Copy)]
+struct Chunking {
+    /// The size of the buffers it goes through.
+    buffer: usize,
+    /// Flush after about this much input, for `progressive_flush()`.
+    flush: Option<usize>,
+    /// Let other tasks run after this many chunks, for `low_priority()`.
+    yield_every: Option<usize>,
+}
+
+/// How many chunks are compressed between yields, with `low_priority()`.
+const LOW_PRIORITY_CHUNKS: usize = 8;
+
+impl Chunking {
+    /// How copies are compressed with `config`.
+    fn of(config: &Config) -> Self {
+        Self {
+            buffer: CachedCompression::BUFFER_SIZE,
+            flush: config.progressive_flush,
+            yield_every: config.low_priority.then_some(LOW_PRIORITY_CHUNKS),
+        }
+    }
+
+    /// Count another chunk, yielding if it's time to.
+    async fn pace(&self, chunks: &mut usize) {
+        *chunks += 1;
+        if self.yield_every.is_some_and(|every| *chunks % every == 0) {
+            rocket::tokio::task::yield_now().await;
+        }
+    }
+}
+
+/// Size of the input blocks compressed independently in parallel mode.
+const PARALLEL_BLOCK: usize = 128 * 1024;
+
+/// The OS recorded in gzip headers, Unix, as zlib records it there. Fixed,
+/// so copies are byte-identical wherever they're built.
+const GZIP_OS: u8 = 3;
+
+/// Metadata recorded in the header of a gzip stream, as restored by `gunzip -N`.
+/// The default records nothing, with a zero modification time.
+#[derive(Clone, Default)]
+struct GzipHeader {
+    filename: Option<Vec<u8>>,
+    mtime: u32,
+}
+
+impl GzipHeader {
+    fn builder(&self) -> GzBuilder {
+        let header = GzBuilder::new().mtime(self.mtime).operating_system(GZIP_OS);
+        match &self.filename {
+            Some(name) => header.filename(name.clone()),
+            None => header,
+        }
+    }
+}
+
+/// A gzip header recording the original name and modification time of `path`.
+fn gzip_header(path: &Path, meta: &std::fs::Metadata) -> GzipHeader {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| u32::try_from(d.as_secs()).unwrap_or(u32::MAX));
+    GzipHeader {
+        filename: path
+            .file_name()
+            .map(|name| name.as_encoded_bytes().to_vec()),
+        mtime,
+    }
+}
+
+/// Write `chunk` of a copy to `output`, passed through `hook` first if set, by
+/// way of `hooked`.
+async fn write_chunk(
+    output: &mut (impl AsyncWrite + Unpin),
+    chunk: &[u8],
+    hook: Option<&PostProcess>,
+    hooked: &mut Vec<u8>,
+) -> io::Result<()> {
+    match hook {
+        Some(hook) if !chunk.is_empty() => {
+            hooked.clear();
+            hooked.extend_from_slice(chunk);
+            hook(hooked);
+            output.write_all(hooked).await
+        }
+        _ => output.write_all(chunk).await,
+    }
+}
+
+/// A unique temporary path for writing `path`, in `dir` if set, or next to
+/// `path` otherwise.
+fn temp_path(path: &Path, dir: Option<&Path>) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{}.tmp", NEXT.fetch_add(1, Ordering::Relaxed)));
+    match dir {
+        Some(dir) => dir.join(name),
+        None => path.with_file_name(name),
+    }
+}
+
+/// Create the directory `path` goes in, and any missing parents. Other tasks
+/// may be creating the same ones, which is no error.
+async fn create_parent(path: &Path) -> io::Result<()> {
+    let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) else {
+        return Ok(());
+    };
+    match rocket::tokio::fs::create_dir_all(dir).await {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Move `from` to `to`, falling back to a copy if they are on different
+/// filesystems.
+async fn move_into_place(from: &Path, to: &Path) -> io::Result<()> {
+    match rocket::tokio::fs::rename(from, to).await {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            // Copied next to `to` first, so replacing it is still atomic
+            let sibling = temp_path(to, None);
+            let res = match rocket::tokio::fs::copy(from, &sibling).await {
+                Ok(_) => rocket::tokio::fs::rename(&sibling, to).await,
+                Err(e) => Err(e),
+            };
+            if res.is_err() {
+                let _ = rocket::tokio::fs::remove_file(&sibling).await;
+            }
+            let _ = rocket::tokio::fs::remove_file(from).await;
+            res
+        }
+        res => res,
+    }
+}
+
+/// Device and inode numbers identifying the file at `path`.
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    id_of(&std::fs::metadata(path).ok()?)
+}
+
+/// Device and inode numbers identifying the file `meta` describes.
+#[cfg(unix)]
+fn id_of(meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn id_of(_meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(unix)]
+async fn set_mode(file: &rocket::tokio::fs::File, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    file.set_permissions(std::fs::Permissions::from_mode(mode))
+        .await
+}
+
+#[cfg(not(unix))]
+async fn set_mode(_file: &rocket::tokio::fs::File, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Add `token` to the `Vary` header, keeping any tokens already listed.
+fn add_vary(headers: &mut HeaderMap<'_>, token: impl Into<Cow<'static, str>>) {
+    let token = token.into();
+    if !headers.contains("Vary") {
+        // The common case, which needn't copy anything
+        headers.add_raw("Vary", token);
+        return;
+    }
+    let token: &str = &token;
+    let mut tokens: Vec<&str> = headers
+        .get("Vary")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .collect();
+    if tokens
+        .iter()
+        .any(|t| *t == "*" || t.eq_ignore_ascii_case(token))
+    {
+        return;
+    }
+    tokens.push(token);
+    let vary = tokens.join(", ");
+    headers.replace(Header::new("Vary", vary));
+}
+
+/// The content type implied by the extension of `path`, as `FileServer` would
+/// detect it, or `None` if the extension is missing or unknown.
+///
+/// ```rust
+/// # use rocket::http::ContentType;
+/// # use rocket_caching_layer::content_type_from_path;
+/// assert_eq!(content_type_from_path("static/app.js"), Some(ContentType::JavaScript));
+/// assert_eq!(content_type_from_path("static/README"), None);
+/// ```
+// This might be a good addition to `ContentType` itself
+pub fn content_type_from_path(path: impl AsRef<Path>) -> Option<ContentType> {
+    ContentType::from_extension(path.as_ref().extension()?.to_str()?)
+}
+
+/// Whether `path` is a file with a name. `FileServer` builds the path from the
+/// request's path segments alone, so it never includes the query string, but
+/// a rewriter could leave it pointing at a root or `..`.
+fn has_file_name(path: &Option<Rewrite<'_>>) -> bool {
+    matches!(path, Some(Rewrite::File(file)) if file.path.file_name().is_some())
+}
+
+/// Whether an earlier rewriter already set a `Content-Encoding` on `path`.
+fn is_encoded(path: &Option<Rewrite<'_>>) -> bool {
+    matches!(path, Some(Rewrite::File(file)) if file.headers.contains("Content-Encoding"))
+}
+
+/// Extensions of files that are already compressed archives, and the subtype
+/// of their `application/*` media type.
+const ARCHIVE_EXTENSIONS: &[(&str, &str)] = &[
+    ("gz", "gzip"),
+    ("tgz", "gzip"),
+    ("zip", "zip"),
+    ("bz2", "x-bzip2"),
+    ("xz", "x-xz"),
+    ("zst", "zstd"),
+    ("7z", "x-7z-compressed"),
+];
+
+/// The media type of `path`, if its extension marks it as a compressed archive.
+fn archive_content_type(path: &Path) -> Option<ContentType> {
+    let ext = path.extension()?.to_str()?;
+    ARCHIVE_EXTENSIONS
+        .iter()
+        .find(|(e, _)| e.eq_ignore_ascii_case(ext))
+        .map(|(_, sub)| ContentType::new("application", *sub))
+}
+
+/// Whether `ct` is the media type of a compressed archive.
+fn is_archive_type(ct: &ContentType) -> bool {
+    ct.top() == "application" && ARCHIVE_EXTENSIONS.iter().any(|(_, sub)| ct.sub() == *sub)
+}
+
+/// Every regular file under `root`, recursively, with its metadata. Symbolic
+/// links aren't followed.
+async fn files_under(root: &Path) -> io::Result<Vec<(PathBuf, std::fs::Metadata)>> {
+    let mut files = vec![];
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = rocket::tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let kind = entry.file_type().await?;
+            if kind.is_dir() {
+                dirs.push(entry.path());
+            } else if kind.is_file() {
+                files.push((entry.path(), entry.metadata().await?));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// The source file, and algorithm, that `artifact` looks like the compressed
+/// copy of, for the cache `version` if set. The source name must have an
+/// extension of its own. Copies for other versions look like copies of a
+/// file named after the version, e.g. `index.txt.v1`.
+fn source_of(artifact: &Path, version: Option<&str>) -> Option<(PathBuf, Algorithm)> {
+    let ext = artifact.extension()?;
+    let algo = Algorithm::ALL.into_iter().find(|a| ext == a.extension())?;
+    let mut source = artifact.with_extension("");
+    if version.is_some_and(|version| source.extension().is_some_and(|ext| ext == version)) {
+        source = source.with_extension("");
+    }
+    source.extension()?;
+    Some((source, algo))
+}
+
+/// Whether the file at `path` starts like a gzip stream.
+async fn has_gzip_magic(path: &Path) -> io::Result<bool> {
+    let mut magic = [0u8; 2];
+    let mut file = rocket::tokio::fs::File::open(path).await?;
+    match file.read_exact(&mut magic).await {
+        Ok(_) => Ok(magic == [0x1f, 0x8b]),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Where the metadata describing the copy stored as `artifact` is written.
+fn metadata_path(artifact: &Path) -> PathBuf {
+    let mut name = artifact.file_name().unwrap_or_default().to_owned();
+    name.push(".meta");
+    artifact.with_file_name(name)
+}
+
+/// A description of a compressed copy, see
+/// [`CachedCompressionBuilder::write_metadata`].
+struct ArtifactMeta {
+    algo: Algorithm,
+    level: u32,
+    original: u64,
+    compressed: u64,
+    /// When the source was last modified, in seconds since the Unix epoch.
+    mtime: u64,
+}
+
+impl ArtifactMeta {
+    /// An ETag for the copy, which changes along with the source.
+    fn etag(&self) -> String {
+        format!(
+            r#""{:x}-{:x}-{}""#,
+            self.mtime,
+            self.original,
+            self.algo.extension()
+        )
+    }
+
+    fn to_json(&self) -> String {
+        // Nothing here needs escaping, other than the quotes in the ETag
+        format!(
+            concat!(
+                r#"{{"algorithm":"{}","level":{},"original_size":{},"compressed_size":{},"#,
+                r#""source_mtime":{},"etag":"{}"}}"#,
+            ),
+            self.algo,
+            self.level,
+            self.original,
+            self.compressed,
+            self.mtime,
+            self.etag().replace('"', r#"\""#),
+        )
+    }
+}
+
+/// Where the compressed copy of `path` for `algo` is stored, for the cache
+/// `version` if set.
+fn artifact_path(path: &Path, algo: Algorithm, version: Option<&str>) -> PathBuf {
+    // Built from the `OsStr`, so names that aren't valid UTF-8 are preserved
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    if let Some(version) = version {
+        name.push(".");
+        name.push(version);
+    }
+    name.push(".");
+    name.push(algo.extension());
+    path.with_file_name(name)
+}
+
+/// Point `file` at its compressed copy for `algo`, held locally at `local`.
+fn serve_compressed(file: &mut File<'_>, algo: Algorithm, local: PathBuf) {
+    // Since we change the path, it seems like we override any
+    // automatic content-type detection, so we just do it manually
+    // We could implement this directly on File as well
+    if let Some(ct) = content_type_from_path(&file.path) {
+        file.headers.replace(ct);
+    }
+    file.headers
+        .replace(Header::new("Content-Encoding", algo.name()));
+    // Any length set by an earlier rewriter is the original's, the body's is
+    // set from the copy
+    file.headers.remove("Content-Length");
+    file.path = local.into();
+}
+
+/// Checks, as Rocket ignites, that the rewriter can work as configured, and
+/// fails the launch otherwise, rather than compressions failing later. Also
+/// applies [`ColdMissPolicy::RetryAfter`] to responses. Attach a clone of the
+/// instance added to the `FileServer`:
+///
+/// ```rust,no_run
+/// # use rocket::fs::FileServer;
+/// # use rocket_caching_layer::CachedCompression;
+/// let cache = CachedCompression::builder().temp_dir("/var/tmp/static").build();
+/// rocket::build()
+///     .attach(cache.clone())
+///     .mount("/", FileServer::new("static").rewrite(cache));
+/// ```
+#[rocket::async_trait]
+impl Fairing for CachedCompression {
+    fn info(&self) -> fairing::Info {
+        fairing::Info {
+            name: "Cached Compression",
+            kind: Kind::Ignite | Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r rocket::Request<'_>, res: &mut Response<'r>) {
+        let Some(retry_after) = req.local_cache(|| ColdMiss(None)).0 else {
+            return;
+        };
+        let secs = retry_after.as_secs_f64().ceil().max(1.) as u64;
+        res.set_status(rocket::http::Status::ServiceUnavailable);
+        res.set_raw_header("Retry-After", secs.to_string());
+        res.remove_header("Content-Type");
+        res.set_sized_body(None, Cursor::new(""));
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        match self.check().await {
+            Ok(()) => Ok(rocket),
+            Err(e) => {
+                error!(target: TARGET, %e, "CachedCompression can't work as configured");
+                Err(rocket)
+            }
+        }
+    }
+}
+
+impl Default for CachedCompression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rewriter for CachedCompression {
+    fn rewrite<'h>(
+        &self,
+        path: Option<Rewrite<'h>>,
+        req: &rocket::Request<'_>,
+    ) -> Option<Rewrite<'h>> {
+        match path {
+            path @ Some(Rewrite::File(_)) if self.bypassed(req) || !self.eligible(req) => path,
+            // Encoded upstream, so encoding it again would corrupt it
+            path @ Some(Rewrite::File(_)) if is_encoded(&path) => path,
+            // Copies are named after the file, so there must be one
+            path @ Some(Rewrite::File(_)) if !has_file_name(&path) => path,
+            Some(Rewrite::File(mut file)) => {
+                if let Some(ct) = archive_content_type(&file.path) {
+                    // Served as a download, rather than something to decompress
+                    if !file.headers.contains("Content-Type") {
+                        file.headers.add(ct);
+                    }
+                    return Some(Rewrite::File(file));
+                }
+                // Only the headers managed here are touched, anything set by an
+                // earlier rewriter is passed through to the response.
+                let only_if_compressible = self.config.vary_only_if_compressible;
+                if !only_if_compressible || self.config.compressible(&file.path) {
+                    add_vary(&mut file.headers, "Accept-Encoding");
+                }
+                for token in &self.config.also_vary_on {
+                    add_vary(&mut file.headers, token.clone());
+                }
+                let accept = req.headers().get("Accept-Encoding");
+                let allowed = self
+                    .config
+                    .serve_compressed_if
+                    .as_ref()
+                    .is_none_or(|f| f(req));
+                match self.negotiate(accept, &file.path, true) {
+                    Decision::ServeCompressed(algo) if allowed => {
+                        self.serve_cached(&mut file, algo)
+                    }
+                    Decision::ServeStale(algo) => {
+                        self.dispatch(algo, file.path.clone().into_owned());
+                        if allowed {
+                            self.serve_cached(&mut file, algo);
+                        }
+                    }
+                    Decision::Fallback { serve, dispatch } => {
+                        self.dispatch(dispatch, file.path.clone().into_owned());
+                        if allowed {
+                            self.serve_cached(&mut file, serve);
+                        }
+                    }
+                    Decision::Dispatch(algo) => {
+                        let popular = self.requested(&file.path);
+                        if popular {
+                            self.dispatch(algo, file.path.clone().into_owned());
+                        }
+                        match self.config.first_hit_wait {
+                            Some(wait)
+                                if allowed && popular && self.wait_for(&file.path, algo, wait) =>
+                            {
+                                self.serve_cached(&mut file, algo)
+                            }
+                            _ => {
+                                self.stats.misses.fetch_add(1, Ordering::Relaxed);
+                                match self.config.cold_miss {
+                                    ColdMissPolicy::RetryAfter(d) if allowed && popular => {
+                                        // Turned into a 503 by `on_response`
+                                        req.local_cache(|| ColdMiss(Some(d)));
+                                    }
+                                    _ => (),
+                                }
+                            }
+                        }
+                    }
+                    Decision::ServeCompressed(_) | Decision::ServeIdentity => (),
+                }
+                Some(Rewrite::File(file))
+            }
+            path => path,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Read, time::Duration};
+
+    use flate2::read::{DeflateDecoder, GzDecoder};
+    use rocket::{
+        async_test, build,
+        fs::{rewrite::DirIndex, FileServer},
+        get,
+        http::{Cookie, Status},
+        local::asynchronous::{Client, LocalResponse},
+        post, routes,
+        tokio::time::sleep,
+        Build, Rocket, State,
+    };
+
+    use super::*;
+
+    /// Where copies are stored without a `cache_version()`.
+    fn artifact_path(path: &Path, algo: Algorithm) -> PathBuf {
+        super::artifact_path(path, algo, None)
+    }
+
+    fn launch() -> Rocket<Build> {
+        launch_in("static", CachedCompression::new())
+    }
+
+    fn launch_in(root: impl AsRef<Path>, cache: CachedCompression) -> Rocket<Build> {
+        build().mount(
+            "/",
+            FileServer::without_index(root.as_ref())
+                .rewrite(DirIndex::unconditional("index.txt"))
+                .rewrite(cache),
+        )
+    }
+
+    /// A scratch directory, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "rocket-caching-layer-{name}-{}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+
+        fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn get<'c>(client: &'c Client, uri: &str, accept: &str) -> LocalResponse<'c> {
+        client
+            .get(uri.to_string())
+            .header(Header::new("Accept-Encoding", accept.to_string()))
+            .dispatch()
+            .await
+    }
+
+    fn gunzip(bytes: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        GzDecoder::new(bytes).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn inflate(bytes: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        DeflateDecoder::new(bytes).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn encoding<'a>(res: &'a LocalResponse<'_>) -> Option<&'a str> {
+        res.headers().get_one("Content-Encoding")
+    }
+
+    async fn gzipped_req(
+        client: &mut Client,
+        accept: impl Into<Option<&'static str>>,
+        compressed: bool,
+    ) {
+        let res = client.get("/");
+        let res = match accept.into() {
+            Some(accept) => res.header(Header::new("Accept-Encoding", accept)),
+            None => res,
+        }
+        .dispatch()
+        .await;
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            res.headers().get_one("Content-Type").unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        if compressed {
+            assert_eq!(res.headers().get_one("Content-Encoding").unwrap(), "gzip");
+            assert_eq!(
+                res.into_bytes().await.unwrap(),
+                include_bytes!("../static/index.txt.pre-gziped")
+            );
+        } else {
+            assert_eq!(res.headers().get_one("Content-Encoding"), None);
+            assert_eq!(
+                res.into_string().await.unwrap(),
+                include_str!("../static/index.txt")
+            );
+        }
+    }
+
+    #[async_test]
+    async fn simple_test() {
+        let mut client = Client::untracked(launch()).await.unwrap();
+        gzipped_req(&mut client, "gzip", false).await;
+        sleep(Duration::from_millis(400)).await;
+
+        gzipped_req(&mut client, "gzip", true).await;
+        gzipped_req(&mut client, "gzip; q=1", true).await;
+        gzipped_req(&mut client, "gzip; q = 0.5", true).await;
+        gzipped_req(&mut client, "gzip; q = 0", false).await;
+        gzipped_req(&mut client, "flate", false).await;
+        gzipped_req(&mut client, "flate,gzip", true).await;
+        gzipped_req(&mut client, None, false).await;
+    }
+
+    #[cfg(unix)]
+    #[async_test]
+    async fn inode_swap_invalidates() {
+        let dir = TestDir::new("inode-swap");
+        dir.write("index.txt", "old contents");
+        let cache = CachedCompression::builder().track_inode().build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), None);
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), Some("gzip"));
+
+        // Swap a new file in under the same name, like a directory swap would
+        let new = dir.write("index.txt.new", "new contents");
+        std::fs::rename(new, dir.path().join("index.txt")).unwrap();
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), "new contents");
+
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), Some("gzip"));
+    }
+
+    #[test]
+    fn decide_variants() {
+        let cache = CachedCompression::new();
+        let path = Path::new("static/index.txt");
+        assert_eq!(cache.decide("", path), Decision::ServeIdentity);
+        assert_eq!(cache.decide("flate", path), Decision::ServeIdentity);
+        assert_eq!(cache.decide("gzip; q=0", path), Decision::ServeIdentity);
+        assert_eq!(
+            cache.decide("gzip", path),
+            Decision::Dispatch(Algorithm::Gzip)
+        );
+
+        cache
+            .map
+            .entry(path.to_path_buf())
+            .or_default()
+            .compressions
+            .push(Algorithm::Gzip);
+        assert_eq!(
+            cache.decide("flate, gzip", path),
+            Decision::ServeCompressed(Algorithm::Gzip)
+        );
+        assert_eq!(cache.decide("flate", path), Decision::ServeIdentity);
+        assert_eq!(
+            cache.decide("deflate-raw, gzip", path),
+            Decision::Fallback {
+                serve: Algorithm::Gzip,
+                dispatch: Algorithm::DeflateRaw
+            }
+        );
+        assert_eq!(
+            cache.decide("gzip", Path::new("static/other.txt")),
+            Decision::Dispatch(Algorithm::Gzip)
+        );
+    }
+
+    #[cfg(unix)]
+    #[async_test]
+    async fn artifact_mode_applied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new("artifact-mode");
+        dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder().artifact_mode(0o600).build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let meta = std::fs::metadata(dir.path().join("index.txt.gzip")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+    }
+
+    #[async_test]
+    async fn bypass_on_cookie() {
+        let dir = TestDir::new("bypass");
+        dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder()
+            .bypass_when(|req| req.cookies().get("debug").is_some_and(|c| c.value() == "1"))
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        let debug = || {
+            client
+                .get("/")
+                .header(Header::new("Accept-Encoding", "gzip"))
+                .cookie(Cookie::new("debug", "1"))
+        };
+
+        // Bypassed requests don't warm the cache
+        debug().dispatch().await;
+        sleep(Duration::from_millis(400)).await;
+        assert!(!dir.path().join("index.txt.gzip").exists());
+
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), Some("gzip"));
+        let res = debug().dispatch().await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), "Hello world");
+    }
+
+    /// Adds a preload hint, like an application might for its assets.
+    struct Preload;
+
+    impl Rewriter for Preload {
+        fn rewrite<'h>(
+            &self,
+            path: Option<Rewrite<'h>>,
+            _req: &rocket::Request<'_>,
+        ) -> Option<Rewrite<'h>> {
+            match path {
+                Some(Rewrite::File(mut file)) => {
+                    file.headers
+                        .add(Header::new("Link", "</style.css>; rel=preload; as=style"));
+                    file.headers.add(Header::new("Vary", "Origin"));
+                    Some(Rewrite::File(file))
+                }
+                path => path,
+            }
+        }
+    }
+
+    #[async_test]
+    async fn preserves_existing_headers() {
+        let dir = TestDir::new("preserve-headers");
+        dir.write("index.txt", "Hello world");
+        let rocket = build().mount(
+            "/",
+            FileServer::without_index(dir.path())
+                .rewrite(DirIndex::unconditional("index.txt"))
+                .rewrite(Preload)
+                .rewrite(CachedCompression::new()),
+        );
+        let client = Client::untracked(rocket).await.unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(
+            res.headers().get_one("Link"),
+            Some("</style.css>; rel=preload; as=style")
+        );
+        assert_eq!(
+            res.headers().get_one("Vary"),
+            Some("Origin, Accept-Encoding")
+        );
+        assert_eq!(
+            res.headers().get_one("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+    }
+
+    #[test]
+    fn vary_is_merged() {
+        let mut file = File::new(Path::new("index.txt"));
+        add_vary(&mut file.headers, "Accept-Encoding");
+        add_vary(&mut file.headers, "accept-encoding");
+        assert_eq!(file.headers.get_one("Vary"), Some("Accept-Encoding"));
+
+        file.headers.replace(Header::new("Vary", "*"));
+        add_vary(&mut file.headers, "Accept-Encoding");
+        assert_eq!(file.headers.get_one("Vary"), Some("*"));
+    }
+
+    #[async_test]
+    async fn recompress_tier_shrinks() {
+        let dir = TestDir::new("recompress-tier");
+        let contents: String = (0..5000)
+            .map(|i| format!("{i}: {}\n", i * 7919 % 10007))
+            .collect();
+        dir.write("index.txt", &contents);
+        let cache = CachedCompression::builder().recompress_tier(1, 9).build();
+        // As if another compression were running, so the upgrade waits
+        let busy = Ticket::new(&cache.stats);
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let artifact = dir.path().join("index.txt.gzip");
+        let first = std::fs::read(&artifact).unwrap();
+        // The header notes the fastest level
+        assert_eq!(first[8], 4);
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), Some("gzip"));
+        assert_eq!(cache.deferred.lock().unwrap().upgrades.len(), 1);
+
+        drop(busy);
+        sleep(IDLE_POLL + Duration::from_millis(400)).await;
+        let upgraded = std::fs::read(&artifact).unwrap();
+        assert_eq!(upgraded[8], 2);
+        assert!(upgraded.len() < first.len());
+        assert_eq!(gunzip(&upgraded), contents.as_bytes());
+        assert!(cache.deferred.lock().unwrap().upgrades.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[async_test]
+    async fn serves_stale_while_revalidating() {
+        let dir = TestDir::new("stale-while-revalidate");
+        dir.write("index.txt", "old contents");
+        let cache = CachedCompression::builder()
+            .track_inode()
+            .stale_while_revalidate()
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let new = dir.write("index.txt.new", "new contents");
+        std::fs::rename(new, dir.path().join("index.txt")).unwrap();
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"old contents");
+
+        sleep(Duration::from_millis(400)).await;
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"new contents");
+    }
+
+    #[async_test]
+    async fn deflate_raw_round_trips() {
+        let dir = TestDir::new("deflate-raw");
+        dir.write("index.txt", "Hello world, hello deflate");
+        let client = Client::untracked(launch_in(dir.path(), CachedCompression::new()))
+            .await
+            .unwrap();
+        get(&client, "/", "deflate-raw").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let res = get(&client, "/", "deflate-raw").await;
+        assert_eq!(encoding(&res), Some("deflate-raw"));
+        let body = res.into_bytes().await.unwrap();
+        assert_ne!(body[..2], [0x1f, 0x8b], "no gzip header");
+        assert_eq!(inflate(&body), b"Hello world, hello deflate");
+    }
+
+    #[async_test]
+    async fn legacy_user_agent_gets_identity() {
+        let dir = TestDir::new("serve-compressed-if");
+        dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder()
+            .serve_compressed_if(|req| {
+                !req.headers()
+                    .get_one("User-Agent")
+                    .is_some_and(|ua| ua.contains("LegacyBrowser"))
+            })
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        let legacy = || {
+            client
+                .get("/")
+                .header(Header::new("Accept-Encoding", "gzip"))
+                .header(Header::new("User-Agent", "LegacyBrowser/1.0"))
+        };
+
+        // Legacy clients still warm the cache for everyone else
+        legacy().dispatch().await;
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), Some("gzip"));
+
+        let res = legacy().dispatch().await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), "Hello world");
+    }
+
+    #[async_test]
+    async fn min_age_defers_new_files() {
+        use std::time::SystemTime;
+
+        let dir = TestDir::new("min-age");
+        let path = dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder()
+            .min_age(Duration::from_secs(60))
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), None);
+        assert!(!dir.path().join("index.txt.gzip").exists());
+
+        // Pretend the file settled a while ago
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(120))
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), Some("gzip"));
+    }
+
+    #[async_test]
+    async fn empty_files_served_as_is() {
+        let dir = TestDir::new("empty-file");
+        let path = dir.write("index.txt", "");
+        let cache = CachedCompression::new();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        assert_eq!(cache.decide("gzip", &path), Decision::ServeIdentity);
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_bytes().await.unwrap(), b"");
+        assert!(!dir.path().join("index.txt.gzip").exists());
+    }
+
+    #[async_test]
+    async fn missing_artifact_reported() {
+        use std::sync::Mutex;
+
+        let dir = TestDir::new("cache-error");
+        dir.write("index.txt", "Hello world");
+        let reported = Arc::new(Mutex::new(vec![]));
+        let cache = CachedCompression::builder()
+            .on_cache_error({
+                let reported = reported.clone();
+                move |path, _err| reported.lock().unwrap().push(path.to_path_buf())
+            })
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let artifact = dir.path().join("index.txt.gzip");
+        std::fs::remove_file(&artifact).unwrap();
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), "Hello world");
+        assert_eq!(*reported.lock().unwrap(), vec![artifact]);
+
+        // The copy is regenerated for later requests
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), Some("gzip"));
+    }
+
+    #[async_test]
+    async fn gzip_metadata_embedded() {
+        use std::time::UNIX_EPOCH;
+
+        let dir = TestDir::new("gzip-metadata");
+        let path = dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder().gzip_embed_metadata().build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let artifact = std::fs::read(dir.path().join("index.txt.gzip")).unwrap();
+        let mut decoder = GzDecoder::new(&artifact[..]);
+        let mut out = String::new();
+        decoder.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "Hello world");
+
+        let header = decoder.header().unwrap();
+        assert_eq!(header.filename(), Some(&b"index.txt"[..]));
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let mtime = mtime.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(header.mtime() as u64, mtime);
+    }
+
+    #[async_test]
+    async fn temp_dir_moved_into_place() {
+        let dir = TestDir::new("temp-dir-source");
+        let temp = TestDir::new("temp-dir-scratch");
+        dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder().temp_dir(temp.path()).build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let artifact = std::fs::read(dir.path().join("index.txt.gzip")).unwrap();
+        assert_eq!(gunzip(&artifact), b"Hello world");
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 0);
+    }
+
+    #[async_test]
+    async fn falls_back_to_cached_encoding() {
+        let dir = TestDir::new("fallback");
+        dir.write("index.txt", "Hello world");
+        let client = Client::untracked(launch_in(dir.path(), CachedCompression::new()))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        // deflate-raw is preferred, but only gzip is cached so far
+        let res = get(&client, "/", "deflate-raw, gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+
+        sleep(Duration::from_millis(400)).await;
+        let res = get(&client, "/", "deflate-raw, gzip").await;
+        assert_eq!(encoding(&res), Some("deflate-raw"));
+    }
+
+    #[get("/data")]
+    fn data(cache: &State<CachedCompression>) -> Compressed {
+        cache.respond(r#"{"hello":"world"}"#.repeat(20), ContentType::JSON)
+    }
+
+    #[async_test]
+    async fn responds_compressed_json() {
+        let rocket = build()
+            .manage(CachedCompression::new())
+            .mount("/", routes![data]);
+        let client = Client::untracked(rocket).await.unwrap();
+        let json = r#"{"hello":"world"}"#.repeat(20);
+
+        let res = get(&client, "/data", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(
+            res.headers().get_one("Content-Type"),
+            Some("application/json")
+        );
+        assert_eq!(res.headers().get_one("Vary"), Some("Accept-Encoding"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), json.as_bytes());
+
+        let res = get(&client, "/data", "identity").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), json);
+    }
+
+    #[get("/archive")]
+    fn archive(cache: &State<CachedCompression>) -> Compressed {
+        let zip = ContentType::new("application", "zip");
+        cache.respond(b"PK\x03\x04".repeat(100), zip)
+    }
+
+    #[async_test]
+    async fn responds_like_cached_copies() {
+        let launch = |cache: CachedCompression| {
+            let rocket = build().manage(cache).mount("/", routes![data, archive]);
+            Client::untracked(rocket)
+        };
+
+        let client = launch(CachedCompression::new()).await.unwrap();
+        assert_eq!(encoding(&get(&client, "/archive", "gzip").await), None);
+
+        let no_json = CachedCompression::builder().exclude("json").build();
+        let client = launch(no_json).await.unwrap();
+        assert_eq!(encoding(&get(&client, "/data", "gzip").await), None);
+        let no_json = CachedCompression::builder()
+            .exclude("application/json")
+            .build();
+        let client = launch(no_json).await.unwrap();
+        assert_eq!(encoding(&get(&client, "/data", "gzip").await), None);
+
+        let elsewhere = CachedCompression::builder()
+            .compress_under("/assets")
+            .build();
+        let client = launch(elsewhere).await.unwrap();
+        let res = get(&client, "/data", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.headers().get_one("Vary"), Some("Accept-Encoding"));
+    }
+
+    #[async_test]
+    async fn compressed_content_length() {
+        let rocket = build()
+            .manage(CachedCompression::new())
+            .mount("/", routes![data]);
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let json = r#"{"hello":"world"}"#.repeat(20);
+        let level = CachedCompression::DEFAULT_LEVEL;
+        let gzip = compress_bytes(Algorithm::Gzip, level, json.as_bytes()).unwrap();
+        let deflate = compress_bytes(Algorithm::DeflateRaw, level, json.as_bytes()).unwrap();
+        // The length of the body as served, which Rocket sends as `Content-Length`
+        for (accept, length) in [("gzip", gzip.len()), ("deflate-raw", deflate.len())] {
+            let res = get(&client, "/data", accept).await;
+            assert_eq!(res.body().preset_size(), Some(length), "{accept}");
+            assert!(length < json.len(), "{accept}");
+            assert_eq!(res.into_bytes().await.unwrap().len(), length, "{accept}");
+        }
+        let res = get(&client, "/data", "identity").await;
+        assert_eq!(res.body().preset_size(), Some(json.len()));
+        // Left for Rocket to add, so it isn't sent twice
+        assert_eq!(res.headers().get("Content-Length").count(), 0);
+    }
+
+    #[async_test]
+    async fn counters_track_requests() {
+        let dir = TestDir::new("counters");
+        dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::new();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        get(&client, "/", "gzip").await;
+        get(&client, "/", "gzip").await;
+        get(&client, "/", "identity").await;
+
+        assert_eq!(
+            cache.counters(),
+            Counters {
+                hits: 2,
+                misses: 1,
+                compressions: 1,
+                failures: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn oversized_accept_encoding_is_bounded() {
+        let cache = CachedCompression::new();
+        let path = Path::new("static/index.txt");
+        let junk = vec!["x-unknown"; 10_000].join(", ");
+        assert_eq!(
+            cache.decide(&format!("gzip, {junk}"), path),
+            Decision::Dispatch(Algorithm::Gzip)
+        );
+        assert_eq!(
+            cache.decide(&format!("{junk}, gzip"), path),
+            Decision::ServeIdentity
+        );
+    }
+
+    #[async_test]
+    async fn first_hit_waits_for_compression() {
+        let dir = TestDir::new("first-hit-wait");
+        dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder()
+            .warm_on_first_hit_blocking(Duration::from_secs(2))
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+    }
+
+    #[async_test]
+    async fn unicode_file_names() {
+        let dir = TestDir::new("unicode-names");
+        dir.write("héllo wörld.txt", "Hello world");
+        let client = Client::untracked(launch_in(dir.path(), CachedCompression::new()))
+            .await
+            .unwrap();
+        let uri = "/h%C3%A9llo%20w%C3%B6rld.txt";
+        get(&client, uri, "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        assert!(dir.path().join("héllo wörld.txt.gzip").is_file());
+        let res = get(&client, uri, "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+    }
+
+    #[cfg(unix)]
+    #[async_test]
+    async fn non_utf8_file_names() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = TestDir::new("non-utf8-names");
+        let path = dir.path().join(OsStr::from_bytes(b"caf\xe9.txt"));
+        std::fs::write(&path, "Hello world").unwrap();
+        let artifact = artifact_path(&path, Algorithm::Gzip);
+        assert_eq!(
+            artifact.file_name().unwrap().as_bytes(),
+            b"caf\xe9.txt.gzip"
+        );
+
+        let cache = CachedCompression::new();
+        cache.dispatch(Algorithm::Gzip, path.clone());
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(gunzip(&std::fs::read(&artifact).unwrap()), b"Hello world");
+        assert_eq!(
+            cache.decide("gzip", &path),
+            Decision::ServeCompressed(Algorithm::Gzip)
+        );
+    }
+
+    #[async_test]
+    async fn extension_differs_from_token() {
+        assert_eq!(Algorithm::DeflateRaw.name(), "deflate-raw");
+        assert_eq!(Algorithm::DeflateRaw.extension(), "deflate");
+
+        let dir = TestDir::new("extension");
+        dir.write("index.txt", "Hello world");
+        let client = Client::untracked(launch_in(dir.path(), CachedCompression::new()))
+            .await
+            .unwrap();
+        get(&client, "/", "deflate-raw").await;
+        sleep(Duration::from_millis(400)).await;
+
+        assert!(dir.path().join("index.txt.deflate").is_file());
+        assert!(!dir.path().join("index.txt.deflate-raw").exists());
+        assert_eq!(
+            encoding(&get(&client, "/", "deflate-raw").await),
+            Some("deflate-raw")
+        );
+
+        // Copies of one file never share a name, even on case-insensitive
+        // file systems
+        for (i, algo) in Algorithm::ALL.iter().enumerate() {
+            let ext = algo.extension();
+            assert!(!Algorithm::ALL[..i]
+                .iter()
+                .any(|a| a.extension().eq_ignore_ascii_case(ext)));
+        }
+    }
+
+    #[async_test]
+    async fn invalidate_prefix_only_removes_subtree() {
+        let dir = TestDir::new("invalidate-prefix");
+        let js = dir.write("js/app.txt", "console.log('hello')");
+        let css = dir.write("css/app.txt", "body { color: red }");
+        let cache = CachedCompression::new();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        get(&client, "/js/app.txt", "gzip").await;
+        get(&client, "/css/app.txt", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        cache.invalidate_prefix(dir.path().join("js")).await;
+        assert!(!dir.path().join("js/app.txt.gzip").exists());
+        assert!(dir.path().join("css/app.txt.gzip").exists());
+        assert_eq!(
+            cache.decide("gzip", &js),
+            Decision::Dispatch(Algorithm::Gzip)
+        );
+        assert_eq!(
+            cache.decide("gzip", &css),
+            Decision::ServeCompressed(Algorithm::Gzip)
+        );
+    }
+
+    #[async_test]
+    async fn transient_failures_retried() {
+        let dir = TestDir::new("retry");
+        let path = dir.write("index.txt", "Hello world");
+        let artifact = artifact_path(&path, Algorithm::Gzip);
+        let config = Config::default();
+
+        let attempts = AtomicU64::new(0);
+        let res = retry(3, Duration::from_millis(10), || {
+            let first = attempts.fetch_add(1, Ordering::Relaxed) == 0;
+            let (path, artifact, config) = (&path, &artifact, &config);
+            async move {
+                if first {
+                    return Err(io::Error::from(io::ErrorKind::Interrupted));
+                }
+                let encoder = Encoder::Stream(Algorithm::Gzip.compressor(9));
+                CachedCompression::compress(encoder, path, artifact, config).await
+            }
+        })
+        .await;
+        res.unwrap();
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert_eq!(gunzip(&std::fs::read(&artifact).unwrap()), b"Hello world");
+
+        // Permanent errors fail straight away
+        let attempts = AtomicU64::new(0);
+        let res: io::Result<()> = retry(3, Duration::from_millis(10), || {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            async { Err(io::Error::from(io::ErrorKind::NotFound)) }
+        })
+        .await;
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[async_test]
+    async fn truncated_artifact_recovered() {
+        let dir = TestDir::new("truncated-artifact");
+        dir.write("index.txt", "Hello world");
+        let client = Client::untracked(launch_in(dir.path(), CachedCompression::new()))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        std::fs::write(dir.path().join("index.txt.gzip"), "").unwrap();
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), "Hello world");
+
+        sleep(Duration::from_millis(400)).await;
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+    }
+
+    #[async_test]
+    async fn parallel_gzip_round_trips() {
+        use flate2::read::MultiGzDecoder;
+
+        let dir = TestDir::new("parallel-gzip");
+        let contents: String = (0..200_000).map(|i| format!("{i} ")).collect();
+        assert!(contents.len() > 4 * PARALLEL_BLOCK);
+        dir.write("index.txt", &contents);
+        let cache = CachedCompression::builder().parallel(4).build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(1000)).await;
+
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        let body = res.into_bytes().await.unwrap();
+        // Several members, the first of which holds exactly one block
+        assert_eq!(gunzip(&body).len(), PARALLEL_BLOCK);
+        let mut out = String::new();
+        MultiGzDecoder::new(&body[..])
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, contents);
+    }
+
+    #[async_test]
+    async fn is_cached_after_dispatch() {
+        let dir = TestDir::new("is-cached");
+        let path = dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::new();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        assert!(!cache.is_cached(&path, Algorithm::Gzip));
+        assert!(!cache.is_pending(&path, Algorithm::Gzip));
+
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        assert!(cache.is_cached(&path, Algorithm::Gzip));
+        assert!(!cache.is_pending(&path, Algorithm::Gzip));
+        assert!(!cache.is_cached(&path, Algorithm::DeflateRaw));
+    }
+
+    #[async_test]
+    async fn archives_not_recompressed() {
+        let dir = TestDir::new("archives");
+        let archive = compress_bytes(Algorithm::Gzip, 9, b"Hello world").unwrap();
+        let path = dir.write("hello.txt.gz", &archive);
+        let client = Client::untracked(launch_in(dir.path(), CachedCompression::new()))
+            .await
+            .unwrap();
+        let res = get(&client, "/hello.txt.gz", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(
+            res.headers().get_one("Content-Type"),
+            Some("application/gzip")
+        );
+        assert_eq!(res.into_bytes().await.unwrap(), archive);
+
+        sleep(Duration::from_millis(400)).await;
+        assert!(!artifact_path(&path, Algorithm::Gzip).exists());
+        assert_eq!(
+            CachedCompression::new().decide("gzip", &path),
+            Decision::ServeIdentity
+        );
+    }
+
+    #[test]
+    fn default_constants() {
+        assert_eq!(CachedCompression::DEFAULT_LEVEL, 9);
+        assert_eq!(CachedCompression::BUFFER_SIZE, 1024);
+        assert_eq!(Algorithm::WINDOW_BITS, 15);
+    }
+
+    #[test]
+    fn events_use_crate_target() {
+        use std::sync::Mutex;
+        use tracing::{span, Event, Metadata, Subscriber};
+
+        /// Records the target of every event.
+        struct Targets(Arc<Mutex<Vec<String>>>);
+
+        impl Subscriber for Targets {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(event.metadata().target().to_string());
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let targets = Arc::new(Mutex::new(vec![]));
+        // A current-thread runtime, so the background task sees the subscriber
+        let rt = rocket::tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        tracing::subscriber::with_default(Targets(targets.clone()), || {
+            rt.block_on(async {
+                let cache = CachedCompression::new();
+                cache.dispatch(Algorithm::Gzip, PathBuf::from("static/does-not-exist.txt"));
+                sleep(Duration::from_millis(200)).await;
+            })
+        });
+        let targets = targets.lock().unwrap();
+        assert!(!targets.is_empty());
+        assert!(targets.iter().all(|t| t == "rocket_caching_layer"));
+    }
+
+    /// Keeps copies in memory, writing them out to `spool` only to serve them.
+    #[derive(Clone)]
+    struct Memory(Arc<std::sync::Mutex<HashMap<PathBuf, Vec<u8>>>>, PathBuf);
+
+    #[rocket::async_trait]
+    impl ArtifactStore for Memory {
+        async fn put(&self, artifact: &Path, file: &Path) -> io::Result<()> {
+            let data = rocket::tokio::fs::read(file).await?;
+            rocket::tokio::fs::remove_file(file).await?;
+            self.0.lock().unwrap().insert(artifact.to_path_buf(), data);
+            Ok(())
+        }
+
+        async fn get(&self, artifact: &Path) -> io::Result<Vec<u8>> {
+            let copies = self.0.lock().unwrap();
+            Ok(copies.get(artifact).ok_or(io::ErrorKind::NotFound)?.clone())
+        }
+
+        async fn exists(&self, artifact: &Path) -> io::Result<bool> {
+            Ok(self.0.lock().unwrap().contains_key(artifact))
+        }
+
+        async fn remove(&self, artifact: &Path) -> io::Result<()> {
+            match self.0.lock().unwrap().remove(artifact) {
+                Some(_) => Ok(()),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+
+        fn local_path<'a>(&self, artifact: &'a Path) -> io::Result<Cow<'a, Path>> {
+            let copies = self.0.lock().unwrap();
+            let data = copies.get(artifact).ok_or(io::ErrorKind::NotFound)?;
+            let local = self.1.join(artifact.file_name().unwrap());
+            std::fs::write(&local, data)?;
+            Ok(local.into())
+        }
+    }
+
+    #[async_test]
+    async fn custom_store_used() {
+        let dir = TestDir::new("custom-store");
+        let spool = TestDir::new("custom-store-spool");
+        let path = dir.write("index.txt", "Hello world");
+        let store = Memory(Default::default(), spool.path().to_path_buf());
+        let cache = CachedCompression::builder().store(store.clone()).build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let artifact = artifact_path(&path, Algorithm::Gzip);
+        assert!(!artifact.exists());
+        assert!(store.exists(&artifact).await.unwrap());
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        let body = res.into_bytes().await.unwrap();
+        assert_eq!(body, store.get(&artifact).await.unwrap());
+        assert_eq!(gunzip(&body), b"Hello world");
+    }
+
+    /// Panics on the first copy stored, and stores the rest on disk.
+    struct PanicOnce(AtomicU64);
+
+    #[rocket::async_trait]
+    impl ArtifactStore for PanicOnce {
+        async fn put(&self, artifact: &Path, file: &Path) -> io::Result<()> {
+            if self.0.fetch_add(1, Ordering::Relaxed) == 0 {
+                panic!("first put");
+            }
+            FileSystem.put(artifact, file).await
+        }
+
+        async fn get(&self, artifact: &Path) -> io::Result<Vec<u8>> {
+            FileSystem.get(artifact).await
+        }
+
+        async fn exists(&self, artifact: &Path) -> io::Result<bool> {
+            FileSystem.exists(artifact).await
+        }
+
+        async fn remove(&self, artifact: &Path) -> io::Result<()> {
+            FileSystem.remove(artifact).await
+        }
+
+        fn local_path<'a>(&self, artifact: &'a Path) -> io::Result<Cow<'a, Path>> {
+            FileSystem.local_path(artifact)
+        }
+    }
+
+    #[async_test]
+    async fn panic_clears_pending() {
+        let dir = TestDir::new("panic-pending");
+        let path = dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder()
+            .store(PanicOnce(AtomicU64::new(0)))
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        assert!(!cache.is_pending(&path, Algorithm::Gzip));
+        assert!(!cache.is_cached(&path, Algorithm::Gzip));
+
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+    }
+
+    /// Fails to store copies with the given extension, and stores the rest on
+    /// disk.
+    struct FailFor(&'static str);
+
+    #[rocket::async_trait]
+    impl ArtifactStore for FailFor {
+        async fn put(&self, artifact: &Path, file: &Path) -> io::Result<()> {
+            if artifact.extension().is_some_and(|ext| ext == self.0) {
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            FileSystem.put(artifact, file).await
+        }
+
+        async fn get(&self, artifact: &Path) -> io::Result<Vec<u8>> {
+            FileSystem.get(artifact).await
+        }
+
+        async fn exists(&self, artifact: &Path) -> io::Result<bool> {
+            FileSystem.exists(artifact).await
+        }
+
+        async fn remove(&self, artifact: &Path) -> io::Result<()> {
+            FileSystem.remove(artifact).await
+        }
+
+        fn local_path<'a>(&self, artifact: &'a Path) -> io::Result<Cow<'a, Path>> {
+            FileSystem.local_path(artifact)
+        }
+    }
+
+    #[async_test]
+    async fn failed_compression_falls_back() {
+        let dir = TestDir::new("fallback-chain");
+        let path = dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder()
+            .store(FailFor("deflate"))
+            .fallback(&[Algorithm::DeflateRaw, Algorithm::Gzip])
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        let res = get(&client, "/", "deflate-raw, gzip").await;
+        assert_eq!(encoding(&res), None);
+        sleep(Duration::from_millis(400)).await;
+
+        assert!(!cache.is_cached(&path, Algorithm::DeflateRaw));
+        assert!(cache.is_cached(&path, Algorithm::Gzip));
+        let decision = cache.decide("deflate-raw, gzip", &path);
+        assert_eq!(decision, Decision::ServeCompressed(Algorithm::Gzip));
+        let res = get(&client, "/", "deflate-raw, gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+
+        // Clients only accepting the failed algorithm still get the original
+        assert_eq!(cache.decide("deflate-raw", &path), Decision::ServeIdentity);
+        assert_eq!(cache.counters().failures, 1);
+    }
+
+    #[async_test]
+    async fn also_varies_on_extra_headers() {
+        let cache = CachedCompression::builder()
+            .also_vary_on(&["Accept-Language"])
+            .build();
+        let client = Client::untracked(launch_in("static", cache)).await.unwrap();
+        let res = get(&client, "/", "gzip").await;
+        let vary: Vec<_> = res.headers().get("Vary").collect();
+        assert_eq!(vary, ["Accept-Encoding, Accept-Language"]);
+    }
+
+    #[async_test]
+    async fn debug_headers_show_ratio() {
+        let dir = TestDir::new("debug-headers");
+        dir.write("index.txt", "Hello world\n".repeat(100));
+        let cache = CachedCompression::builder().debug_headers().build();
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(res.headers().get_one("X-Compression-Ratio"), None);
+        sleep(Duration::from_millis(400)).await;
+
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        let ratio: f64 = res
+            .headers()
+            .get_one("X-Compression-Ratio")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(ratio > 0.0 && ratio < 1.0, "{ratio}");
+    }
+
+    #[test]
+    fn content_types_from_extensions() {
+        let txt = content_type_from_path("index.txt").unwrap();
+        assert_eq!(txt.to_string(), "text/plain; charset=utf-8");
+        assert_eq!(
+            content_type_from_path("a/b/style.CSS"),
+            Some(ContentType::CSS)
+        );
+        assert_eq!(
+            content_type_from_path(Path::new("data.json")),
+            Some(ContentType::JSON)
+        );
+        assert_eq!(content_type_from_path("archive.unknown-ext"), None);
+        assert_eq!(content_type_from_path("Makefile"), None);
+        assert_eq!(content_type_from_path(".hidden"), None);
+    }
+
+    #[test]
+    fn parse_tricky_accept_encodings() {
+        let cases: &[(&str, &[(&str, f32)])] = &[
+            ("", &[]),
+            (" , ,, ", &[]),
+            ("gzip", &[("gzip", 1.)]),
+            (" gzip ; q=0.5 ", &[("gzip", 0.5)]),
+            ("gzip;Q=0", &[("gzip", 0.)]),
+            ("gzip;q=", &[("gzip", 0.)]),
+            ("gzip;q", &[("gzip", 1.)]),
+            ("gzip;q=NaN", &[("gzip", 0.)]),
+            ("gzip;q=inf", &[("gzip", 0.)]),
+            ("gzip;q=2", &[("gzip", 0.)]),
+            ("gzip;q=-1", &[("gzip", 0.)]),
+            ("gzip;q=0.2;q=0.9", &[("gzip", 0.2)]),
+            ("gzip;level=0", &[("gzip", 1.)]),
+            (";q=1, gzip", &[("gzip", 1.)]),
+            (
+                "gzip, deflate-raw;q=0.1",
+                &[("gzip", 1.), ("deflate-raw", 0.1)],
+            ),
+        ];
+        for (header, expected) in cases {
+            let expected: Vec<_> = expected.iter().map(|(c, q)| (c.to_string(), *q)).collect();
+            assert_eq!(parse_accept_encoding(header), expected, "{header:?}");
+        }
+        assert_eq!(parse_accept_encoding(&"x, ".repeat(100)).len(), MAX_CODINGS);
+    }
+
+    #[async_test]
+    async fn head_matches_get() {
+        let dir = TestDir::new("head");
+        let path = dir.write("index.txt", "Hello world\n".repeat(100));
+        let client = Client::untracked(launch_in(dir.path(), CachedCompression::new()))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let head = client
+            .head("/")
+            .header(Header::new("Accept-Encoding", "gzip"))
+            .dispatch()
+            .await;
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(head.status(), Status::Ok);
+        for name in ["Content-Encoding", "Content-Type", "Vary"] {
+            let head: Vec<_> = head.headers().get(name).collect();
+            let res: Vec<_> = res.headers().get(name).collect();
+            assert_eq!(head, res, "{name}");
+        }
+        assert_eq!(encoding(&head), Some("gzip"));
+        let artifact = std::fs::read(artifact_path(&path, Algorithm::Gzip)).unwrap();
+        assert_eq!(res.into_bytes().await.unwrap(), artifact);
+        assert!(head.into_bytes().await.unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn recompress_all_regenerates() {
+        let dir = TestDir::new("recompress-all");
+        let manifest = dir.write(
+            "compression.toml",
+            "[[override]]\nfiles = \"txt\"\nlevel = 1\n",
+        );
+        let contents: String = (0..5000)
+            .map(|i| format!("{i}: {}\n", i * 7919 % 10007))
+            .collect();
+        let paths = [dir.write("a.txt", &contents), dir.write("b.txt", &contents)];
+        // One at a time, so the second has to wait for room rather than be dropped
+        let cache = CachedCompression::builder()
+            .manifest(dir.path())
+            .max_pending(1)
+            .build();
+        for path in &paths {
+            assert!(cache.warm_file(path, Algorithm::Gzip).await);
+        }
+        let read = |path: &Path| std::fs::read(artifact_path(path, Algorithm::Gzip)).unwrap();
+        let quick: Vec<_> = paths.iter().map(|path| read(path)).collect();
+
+        // The level is raised
+        std::fs::write(&manifest, "[[override]]\nfiles = \"txt\"\nlevel = 9\n").unwrap();
+        let later = std::time::SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&manifest)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        cache.recompress_all().await;
+        for (path, quick) in paths.iter().zip(quick) {
+            let regenerated = read(path);
+            assert!(regenerated.len() < quick.len(), "{}", path.display());
+            assert_eq!(gunzip(&regenerated), contents.as_bytes());
+        }
+    }
+
+    #[test]
+    fn duplicate_codings_refused_by_q0() {
+        let cache = CachedCompression::new();
+        let cases = [
+            ("gzip, gzip;q=0", None),
+            ("gzip;q=0, gzip", None),
+            (
+                "gzip;q=0.5, deflate-raw, gzip;q=0",
+                Some(Algorithm::DeflateRaw),
+            ),
+            // Repeating an accepted coding changes nothing
+            ("gzip, gzip;q=0.5", Some(Algorithm::Gzip)),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(
+                cache.get_valid(std::iter::once(header)),
+                expected,
+                "{header}"
+            );
+        }
+        let path = Path::new("static/index.txt");
+        assert_eq!(
+            cache.decide("gzip;q=0, gzip", path),
+            Decision::ServeIdentity
+        );
+    }
+
+    #[post("/warm")]
+    async fn warm(
+        cache: &State<CachedCompression>,
+        root: &State<PathBuf>,
+    ) -> Result<WarmStatus, Error> {
+        cache.warm(root.inner()).await
+    }
+
+    async fn warm_status(client: &Client) -> serde_json::Value {
+        let res = client.post("/admin/warm").dispatch().await;
+        assert_eq!(res.content_type(), Some(ContentType::JSON));
+        serde_json::from_str(&res.into_string().await.unwrap()).unwrap()
+    }
+
+    #[async_test]
+    async fn warm_reports_json_status() {
+        let dir = TestDir::new("warm");
+        dir.write("index.txt", "Hello world");
+        dir.write("css/site.css", "body { color: red }");
+        dir.write("empty.txt", "");
+        dir.write("bundle.tar.gz", "not really gzip");
+        let cache = CachedCompression::new();
+        let rocket = launch_in(dir.path(), cache.clone())
+            .mount("/admin", routes![warm])
+            .manage(cache)
+            .manage(dir.path().to_path_buf());
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let status = warm_status(&client).await;
+        assert_eq!(
+            status,
+            serde_json::json!({ "total": 2, "queued": 2, "completed": 0 })
+        );
+        sleep(Duration::from_millis(400)).await;
+
+        let status = warm_status(&client).await;
+        assert_eq!(
+            status,
+            serde_json::json!({ "total": 2, "queued": 0, "completed": 2 })
+        );
+        assert!(dir.path().join("css/site.css.gzip").exists());
+        assert!(!dir.path().join("empty.txt.gzip").exists());
+    }
+
+    /// Serves every file as a gzip stream, as if it were stored compressed.
+    struct Pregzipped;
+
+    impl Rewriter for Pregzipped {
+        fn rewrite<'h>(
+            &self,
+            path: Option<Rewrite<'h>>,
+            _req: &rocket::Request<'_>,
+        ) -> Option<Rewrite<'h>> {
+            match path {
+                Some(Rewrite::File(mut file)) => {
+                    file.headers.add(Header::new("Content-Encoding", "gzip"));
+                    Some(Rewrite::File(file))
+                }
+                path => path,
+            }
+        }
+    }
+
+    #[async_test]
+    async fn upstream_encoding_passed_through() {
+        let dir = TestDir::new("upstream-encoding");
+        let gzipped = compress_bytes(Algorithm::Gzip, 9, b"Hello world").unwrap();
+        let path = dir.write("index.txt", &gzipped);
+        let rocket = build().mount(
+            "/",
+            FileServer::without_index(dir.path())
+                .rewrite(DirIndex::unconditional("index.txt"))
+                .rewrite(Pregzipped)
+                .rewrite(CachedCompression::new()),
+        );
+        let client = Client::untracked(rocket).await.unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let res = get(&client, "/", "gzip").await;
+        let encodings: Vec<_> = res.headers().get("Content-Encoding").collect();
+        assert_eq!(encodings, ["gzip"]);
+        assert_eq!(res.headers().get_one("Vary"), None);
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+        assert!(!artifact_path(&path, Algorithm::Gzip).exists());
+    }
+
+    #[async_test]
+    async fn adaptive_prefers_better_ratio() {
+        let dir = TestDir::new("adaptive");
+        for i in 0..3 {
+            let json = format!(r#"{{"id": {i}, "tags": ["{}"]}}"#, "tag".repeat(50));
+            dir.write(&format!("{i}.json"), json);
+        }
+        let new = dir.write("new.json", r#"{"id": 3, "tags": []}"#);
+        let cache = CachedCompression::builder().adaptive().build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.decide("gzip, deflate-raw", &new),
+            Decision::Dispatch(Algorithm::Gzip)
+        );
+
+        for i in 0..3 {
+            get(&client, &format!("/{i}.json"), "gzip").await;
+            get(&client, &format!("/{i}.json"), "deflate-raw").await;
+        }
+        sleep(Duration::from_millis(400)).await;
+        // Without the gzip header and trailer, deflate-raw is always smaller
+        let decision = cache.decide("gzip, deflate-raw", &new);
+        assert_eq!(decision, Decision::Dispatch(Algorithm::DeflateRaw));
+        let other = dir.path().join("new.txt");
+        assert_eq!(
+            cache.decide("gzip, deflate-raw", &other),
+            Decision::Dispatch(Algorithm::Gzip)
+        );
+        // Only among encodings the client weighs equally
+        let decision = cache.decide("gzip;q=1, deflate-raw;q=0.1", &new);
+        assert_eq!(decision, Decision::Dispatch(Algorithm::Gzip));
+
+        // An algorithm without samples yet gets to compress some
+        for i in 0..3 {
+            dir.write(&format!("{i}.html"), format!("<p>{}</p>", "tag".repeat(50)));
+            get(&client, &format!("/{i}.html"), "gzip").await;
+        }
+        sleep(Duration::from_millis(400)).await;
+        let page = dir.path().join("new.html");
+        let decision = cache.decide("gzip, deflate-raw", &page);
+        assert_eq!(decision, Decision::Dispatch(Algorithm::DeflateRaw));
+    }
+
+    #[test]
+    fn finish_with_tiny_buffer() {
+        let data = "Hello world\n".repeat(100);
+        let mut compressor = Algorithm::Gzip.compressor(9);
+        let mut out = Vec::with_capacity(data.len());
+        compressor
+            .compress_vec(data.as_bytes(), &mut out, FlushCompress::None)
+            .unwrap();
+        assert_eq!(compressor.total_in(), data.len() as u64);
+
+        let mut buf = [0u8; 3];
+        loop {
+            let (size, done) = finish_step(&mut compressor, &mut buf).unwrap();
+            out.extend_from_slice(&buf[..size]);
+            if done {
+                break;
+            }
+        }
+        assert_eq!(gunzip(&out), data.as_bytes());
+
+        // With nowhere to write, it fails rather than spinning
+        let mut compressor = Algorithm::Gzip.compressor(9);
+        assert!(finish_step(&mut compressor, &mut []).is_err());
+    }
+
+    #[async_test]
+    async fn global_shared_between_mounts() {
+        let dir = TestDir::new("global");
+        dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder().debug_headers().build();
+        assert!(CachedCompression::init_global(cache).is_ok());
+        assert!(CachedCompression::init_global(CachedCompression::new()).is_err());
+
+        let server = || {
+            FileServer::without_index(dir.path())
+                .rewrite(DirIndex::unconditional("index.txt"))
+                .rewrite(CachedCompression::global().clone())
+        };
+        let rocket = build().mount("/a", server()).mount("/b", server());
+        let client = Client::untracked(rocket).await.unwrap();
+        get(&client, "/a", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let res = get(&client, "/b", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        // Configured by `init_global`, not the defaults
+        assert!(res.headers().get_one("X-Compression-Ratio").is_some());
+        assert_eq!(CachedCompression::global().counters().hits, 1);
+    }
+
+    #[async_test]
+    async fn prune_removes_only_orphans() {
+        let dir = TestDir::new("prune");
+        let gzipped = compress_bytes(Algorithm::Gzip, 9, b"Hello world").unwrap();
+        dir.write("kept.txt", "Hello world");
+        dir.write("kept.txt.gzip", &gzipped);
+        dir.write("css/gone.css.gzip", &gzipped);
+        dir.write("css/gone.css.deflate", "deflated");
+        // Not copies of anything, even though nothing matches their name
+        dir.write("backup.tar.gzip", "not gzip");
+        dir.write("notes.gzip", &gzipped);
+
+        let cache = CachedCompression::new();
+        assert_eq!(cache.prune_orphans(dir.path()).await.unwrap(), 2);
+        assert!(dir.path().join("kept.txt.gzip").exists());
+        assert!(!dir.path().join("css/gone.css.gzip").exists());
+        assert!(!dir.path().join("css/gone.css.deflate").exists());
+        assert!(dir.path().join("backup.tar.gzip").exists());
+        assert!(dir.path().join("notes.gzip").exists());
+    }
+
+    #[async_test]
+    async fn marginal_savings_served_as_is() {
+        let dir = TestDir::new("min-savings");
+        // Mostly noise, with a little redundancy to squeeze out
+        let mut seed = 0x2545_f491_u32;
+        let mut contents: Vec<u8> = (0..8192)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 24) as u8
+            })
+            .collect();
+        contents.extend(vec![b'a'; 512]);
+        let path = dir.write("noise.bin", &contents);
+        let cache = CachedCompression::builder().min_savings_ratio(0.2).build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        get(&client, "/noise.bin", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        assert!(!artifact_path(&path, Algorithm::Gzip).exists());
+        assert_eq!(cache.decide("gzip", &path), Decision::ServeIdentity);
+        let res = get(&client, "/noise.bin", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_bytes().await.unwrap(), contents);
+        // Only gzip fell short, the other algorithm is still tried
+        let both = cache.decide("gzip, deflate-raw", &path);
+        assert_eq!(both, Decision::Dispatch(Algorithm::DeflateRaw));
+    }
+
+    #[test]
+    fn negotiation_matrix() {
+        use Algorithm::*;
+
+        let cache = CachedCompression::new();
+        let cases: &[(&str, &[Algorithm])] = &[
+            // Unsupported only, or nothing at all
+            ("", &[]),
+            ("identity", &[]),
+            ("br", &[]),
+            ("br, deflate, zstd", &[]),
+            // Every quality zero
+            ("gzip;q=0, deflate-raw;q=0", &[]),
+            ("*;q=0", &[]),
+            ("gzip;q=0, *;q=0", &[]),
+            // Equal weights keep the client's order
+            ("br, gzip, deflate", &[Gzip]),
+            ("gzip, deflate-raw", &[Gzip, DeflateRaw]),
+            ("deflate-raw, gzip", &[DeflateRaw, Gzip]),
+            // Mixed weights, highest first
+            ("gzip;q=0.5, deflate-raw", &[DeflateRaw, Gzip]),
+            ("gzip;q=0.8, deflate-raw;q=0.9, br;q=1", &[DeflateRaw, Gzip]),
+            ("deflate-raw;q=0.001, gzip;q=0.002", &[Gzip, DeflateRaw]),
+            (
+                "gzip;q=0.5, gzip;q=0.9, deflate-raw;q=0.7",
+                &[Gzip, DeflateRaw],
+            ),
+            // Wildcards
+            ("*", &[Gzip, DeflateRaw]),
+            ("deflate-raw, *", &[DeflateRaw, Gzip]),
+            ("*;q=0.5, deflate-raw;q=0.1", &[Gzip, DeflateRaw]),
+            ("gzip;q=0, *", &[DeflateRaw]),
+            // Case and whitespace in parameters
+            (" gzip ; Q=0.3 ,deflate-raw;q=0.2", &[Gzip, DeflateRaw]),
+        ];
+        for (header, expected) in cases {
+            let got: Vec<_> = cache.acceptable(std::iter::once(*header)).collect();
+            assert_eq!(got, *expected, "{header:?}");
+            assert_eq!(
+                cache.get_valid(std::iter::once(*header)),
+                expected.first().copied()
+            );
+        }
+    }
+
+    #[rocket::async_test]
+    async fn metadata_describes_artifact() {
+        use std::time::UNIX_EPOCH;
+
+        let dir = TestDir::new("metadata");
+        let path = dir.write("index.txt", "Hello world! ".repeat(100));
+        let cache = CachedCompression::builder().write_metadata().build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        get(&client, "/index.txt", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let artifact = artifact_path(&path, Algorithm::Gzip);
+        let meta_path = dir.path().join("index.txt.gzip.meta");
+        let meta: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&meta_path).unwrap()).unwrap();
+        let source = std::fs::metadata(&path).unwrap();
+        let mtime = source
+            .modified()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let compressed = std::fs::metadata(&artifact).unwrap().len();
+        assert_eq!(meta["algorithm"], "gzip");
+        assert_eq!(meta["level"], 9);
+        assert_eq!(meta["original_size"], source.len());
+        assert_eq!(meta["compressed_size"], compressed);
+        assert_eq!(meta["source_mtime"], mtime);
+        let etag = format!(r#""{mtime:x}-{:x}-gzip""#, source.len());
+        assert_eq!(meta["etag"], etag);
+        let contents = gunzip(&std::fs::read(&artifact).unwrap());
+        assert_eq!(contents, "Hello world! ".repeat(100).into_bytes());
+
+        // Not compressed as a source, nor left behind once invalidated
+        assert!(!cache.worth_warming(&meta_path));
+        cache.invalidate_prefix(dir.path()).await;
+        assert!(!meta_path.exists());
+    }
+
+    #[rocket::async_test]
+    async fn deferred_until_window_opens() {
+        use std::time::UNIX_EPOCH;
+
+        let dir = TestDir::new("window");
+        let path = dir.write("index.txt", "Hello world");
+        // Seconds into the day, starting before the window
+        let now = Arc::new(AtomicU64::new(3600));
+        let clock = now.clone();
+        let cache = CachedCompression::builder()
+            .compress_window(Duration::from_secs(2 * 3600), Duration::from_secs(4 * 3600))
+            .clock(move || UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::Relaxed)))
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+
+        let res = get(&client, "/index.txt", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        sleep(Duration::from_millis(400)).await;
+        assert!(!cache.is_cached(&path, Algorithm::Gzip));
+        assert!(!artifact_path(&path, Algorithm::Gzip).exists());
+
+        now.store(3 * 3600, Ordering::Relaxed);
+        sleep(WINDOW_POLL + Duration::from_millis(400)).await;
+        assert!(cache.is_cached(&path, Algorithm::Gzip));
+        let res = get(&client, "/index.txt", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert!(cache.deferred.lock().unwrap().jobs.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn manifest_overrides_level() {
+        let dir = TestDir::new("manifest");
+        let manifest = dir.write(
+            "compression.toml",
+            "[[override]]\nfiles = \"txt\"\nlevel = 1\n\n\
+             [[override]]\nfiles = \"css\"\nalgorithm = \"deflate-raw\"\n",
+        );
+        dir.write("index.txt", "Hello world! ".repeat(100));
+        dir.write("site.css", "body { color: red } ".repeat(100));
+        dir.write("app.js", "let x = 1; ".repeat(100));
+        let cache = CachedCompression::builder()
+            .manifest(dir.path())
+            .write_metadata()
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        let level = |name: &str| {
+            let meta = std::fs::read(dir.path().join(name)).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&meta).unwrap()["level"].clone()
+        };
+
+        get(&client, "/index.txt", "gzip").await;
+        get(&client, "/app.js", "gzip").await;
+        assert_eq!(
+            cache.decide("gzip, deflate-raw", &dir.path().join("site.css")),
+            Decision::Dispatch(Algorithm::DeflateRaw)
+        );
+        assert_eq!(
+            cache.decide("gzip", &dir.path().join("site.css")),
+            Decision::ServeIdentity
+        );
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(level("index.txt.gzip.meta"), 1);
+        assert_eq!(level("app.js.gzip.meta"), 9);
+
+        // Changes are picked up on the next compression
+        std::fs::write(&manifest, "[[override]]\nfiles = \"txt\"\nlevel = 5\n").unwrap();
+        let later = std::time::SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&manifest)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        cache.invalidate_prefix(dir.path()).await;
+        get(&client, "/index.txt", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(level("index.txt.gzip.meta"), 5);
+    }
+
+    #[rocket::catch(404)]
+    async fn not_found(req: &rocket::Request<'_>) -> Option<CachedFile> {
+        let cache = req.rocket().state::<CachedCompression>()?;
+        let root = req.rocket().state::<PathBuf>()?;
+        cache.file(root.join("404.html"), req).await.ok()
+    }
+
+    #[async_test]
+    async fn error_pages_compressed() {
+        let dir = TestDir::new("error-page");
+        let page = "<h1>Not found</h1>".repeat(50);
+        dir.write("404.html", &page);
+        let cache = CachedCompression::new();
+        let rocket = launch_in(dir.path(), cache.clone())
+            .register("/", rocket::catchers![not_found])
+            .manage(cache)
+            .manage(dir.path().to_path_buf());
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let res = get(&client, "/missing.html", "gzip").await;
+        assert_eq!(res.status(), Status::NotFound);
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), page);
+        sleep(Duration::from_millis(400)).await;
+
+        let res = get(&client, "/missing.html", "gzip").await;
+        assert_eq!(res.status(), Status::NotFound);
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(
+            res.headers().get_one("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(res.headers().get_one("Vary"), Some("Accept-Encoding"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), page.as_bytes());
+    }
+
+    /// Takes a while to store each copy, and stores them on disk.
+    struct Slow;
+
+    #[rocket::async_trait]
+    impl ArtifactStore for Slow {
+        async fn put(&self, artifact: &Path, file: &Path) -> io::Result<()> {
+            sleep(Duration::from_millis(500)).await;
+            FileSystem.put(artifact, file).await
+        }
+
+        async fn get(&self, artifact: &Path) -> io::Result<Vec<u8>> {
+            FileSystem.get(artifact).await
+        }
+
+        async fn exists(&self, artifact: &Path) -> io::Result<bool> {
+            FileSystem.exists(artifact).await
+        }
+
+        async fn remove(&self, artifact: &Path) -> io::Result<()> {
+            FileSystem.remove(artifact).await
+        }
+
+        fn local_path<'a>(&self, artifact: &'a Path) -> io::Result<Cow<'a, Path>> {
+            FileSystem.local_path(artifact)
+        }
+    }
+
+    #[async_test]
+    async fn max_pending_sheds_misses() {
+        let dir = TestDir::new("max-pending");
+        let first = dir.write("first.txt", "Hello world");
+        let second = dir.write("second.txt", "Hello again");
+        let cache = CachedCompression::builder()
+            .store(Slow)
+            .max_pending(1)
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+
+        get(&client, "/first.txt", "gzip").await;
+        sleep(Duration::from_millis(100)).await;
+        let res = get(&client, "/second.txt", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        sleep(Duration::from_millis(100)).await;
+        assert!(cache.is_pending(&first, Algorithm::Gzip));
+        assert!(!cache.is_pending(&second, Algorithm::Gzip));
+        assert_eq!(cache.stats.scheduled.load(Ordering::Relaxed), 1);
+
+        // Drained, so the next miss is compressed
+        sleep(Duration::from_millis(600)).await;
+        assert!(cache.is_cached(&first, Algorithm::Gzip));
+        assert!(!cache.is_cached(&second, Algorithm::Gzip));
+        assert_eq!(cache.stats.scheduled.load(Ordering::Relaxed), 0);
+        get(&client, "/second.txt", "gzip").await;
+        sleep(Duration::from_millis(800)).await;
+        assert!(cache.is_cached(&second, Algorithm::Gzip));
+    }
+
+    #[async_test]
+    async fn gzip_output_is_reproducible() {
+        let dir = TestDir::new("reproducible");
+        let path = dir.write("index.txt", "Hello world! ".repeat(1000));
+        let artifact = artifact_path(&path, Algorithm::Gzip);
+        let mut outputs = vec![];
+        for parallel in [None, Some(2)] {
+            for _ in 0..2 {
+                let mut builder = CachedCompression::builder();
+                if let Some(threads) = parallel {
+                    builder = builder.parallel(threads);
+                }
+                let cache = builder.build();
+                cache.compression(Algorithm::Gzip, path.clone()).await;
+                outputs.push(std::fs::read(&artifact).unwrap());
+                // Later runs mustn't pick up anything from the source's mtime
+                let later = std::time::SystemTime::now() + Duration::from_secs(60);
+                std::fs::File::options()
+                    .write(true)
+                    .open(&path)
+                    .unwrap()
+                    .set_modified(later)
+                    .unwrap();
+            }
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[2], outputs[3]);
+        // No modification time, and Unix, as zlib records there
+        assert_eq!(&outputs[0][4..8], [0; 4]);
+        assert_eq!(outputs[0][9], 3);
+    }
+
+    #[get("/stream/<name>")]
+    async fn stream(
+        name: &str,
+        cache: &State<CachedCompression>,
+        root: &State<PathBuf>,
+    ) -> io::Result<Streamed> {
+        cache.stream_without_cache(root.join(name)).await
+    }
+
+    #[async_test]
+    async fn streams_without_caching() {
+        let dir = TestDir::new("stream");
+        let contents: String = (0..100_000).map(|i| format!("{i}\n")).collect();
+        let path = dir.write("big.txt", &contents);
+        let cache = CachedCompression::new();
+        let rocket = build()
+            .mount("/", routes![stream])
+            .manage(cache.clone())
+            .manage(dir.path().to_path_buf());
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let res = get(&client, "/stream/big.txt", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(
+            res.headers().get_one("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(
+            gunzip(&res.into_bytes().await.unwrap()),
+            contents.as_bytes()
+        );
+
+        let res = get(&client, "/stream/big.txt", "identity").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), contents);
+
+        sleep(Duration::from_millis(400)).await;
+        assert!(!artifact_path(&path, Algorithm::Gzip).exists());
+        assert!(cache.map.is_empty());
+        assert_eq!(cache.counters(), Counters::default());
+    }
+
+    #[async_test]
+    async fn streamed_like_cached_copies() {
+        let dir = TestDir::new("stream-options");
+        let contents: String = (0..10_000).map(|i| format!("{i}\n")).collect();
+        dir.write("big.txt", &contents);
+        dir.write("big.csv", &contents);
+        let launch = |cache: CachedCompression| {
+            let rocket = build()
+                .mount("/", routes![stream])
+                .manage(cache)
+                .manage(dir.path().to_path_buf());
+            Client::untracked(rocket)
+        };
+
+        let client = launch(CachedCompression::builder().exclude("csv").build())
+            .await
+            .unwrap();
+        let res = get(&client, "/stream/big.txt", "gzip").await;
+        let body = res.into_bytes().await.unwrap();
+        // The header notes the best level, the default
+        assert_eq!(body[8], 2);
+        assert_eq!(gunzip(&body), contents.as_bytes());
+        assert_eq!(
+            encoding(&get(&client, "/stream/big.csv", "gzip").await),
+            None
+        );
+
+        let tiered = CachedCompression::builder().recompress_tier(1, 9).build();
+        let client = launch(tiered).await.unwrap();
+        let body = get(&client, "/stream/big.txt", "gzip")
+            .await
+            .into_bytes()
+            .await
+            .unwrap();
+        assert_eq!(body[8], 4);
+
+        let elsewhere = CachedCompression::builder()
+            .compress_under("/assets")
+            .build();
+        let client = launch(elsewhere).await.unwrap();
+        assert_eq!(
+            encoding(&get(&client, "/stream/big.txt", "gzip").await),
+            None
+        );
+    }
+
+    #[async_test]
+    async fn query_strings_ignored() {
+        let dir = TestDir::new("query");
+        let path = dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::new();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        get(&client, "/index.txt?v=1&file=../other.txt", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["index.txt", "index.txt.gzip"]);
+        assert!(cache.is_cached(&path, Algorithm::Gzip));
+        let res = get(&client, "/index.txt?v=2", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+
+        // Outside of a `FileServer`, a path without a file name is left alone
+        let req = client
+            .get("/")
+            .header(Header::new("Accept-Encoding", "gzip"));
+        let file = Some(Rewrite::File(File::new(Path::new("/"))));
+        let Some(Rewrite::File(file)) = cache.rewrite(file, req.inner()) else {
+            panic!("not a file");
+        };
+        assert_eq!(file.path, Path::new("/"));
+        assert!(file.headers.is_empty());
+    }
+
+    #[async_test]
+    async fn algorithm_chosen_by_size() {
+        let dir = TestDir::new("size-based");
+        let small = dir.write("small.txt", "Hello world");
+        let large = dir.write("large.txt", "Hello world\n".repeat(1000));
+        let cache = CachedCompression::builder()
+            .size_based_algorithm(1024, Algorithm::Gzip, Algorithm::DeflateRaw)
+            .build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+
+        let accept = "gzip, deflate-raw";
+        assert_eq!(
+            cache.decide(accept, &small),
+            Decision::Dispatch(Algorithm::Gzip)
+        );
+        assert_eq!(
+            cache.decide(accept, &large),
+            Decision::Dispatch(Algorithm::DeflateRaw)
+        );
+        // Only among the accepted encodings, weighed equally
+        assert_eq!(
+            cache.decide("gzip", &large),
+            Decision::Dispatch(Algorithm::Gzip)
+        );
+        let decision = cache.decide("gzip, deflate-raw;q=0.5", &large);
+        assert_eq!(decision, Decision::Dispatch(Algorithm::Gzip));
+
+        get(&client, "/small.txt", accept).await;
+        get(&client, "/large.txt", accept).await;
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(
+            encoding(&get(&client, "/small.txt", accept).await),
+            Some("gzip")
+        );
+        assert_eq!(
+            encoding(&get(&client, "/large.txt", accept).await),
+            Some("deflate-raw")
+        );
+        assert!(!cache.is_cached(&large, Algorithm::Gzip));
+    }
+
+    #[async_test]
+    async fn shutdown_waits_for_compressions() {
+        let dir = TestDir::new("shutdown");
+        let paths: Vec<_> = (0..5)
+            .map(|i| dir.write(&format!("{i}.txt"), format!("Hello world {i}")))
+            .collect();
+        let cache = CachedCompression::builder().store(Slow).build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        for i in 0..5 {
+            get(&client, &format!("/{i}.txt"), "gzip").await;
+        }
+
+        cache.shutdown(false).await;
+        for path in &paths {
+            assert!(cache.is_cached(path, Algorithm::Gzip));
+            assert!(artifact_path(path, Algorithm::Gzip).exists());
+        }
+        cache.shutdown(true).await;
+        for path in &paths {
+            assert!(!cache.is_cached(path, Algorithm::Gzip));
+            assert!(!artifact_path(path, Algorithm::Gzip).exists());
+        }
+    }
+
+    #[async_test]
+    async fn progressive_flush_decodes_early() {
+        let dir = TestDir::new("progressive");
+        let contents: String = (0..20_000)
+            .map(|i| format!("{}\n", i * 7919 % 10007))
+            .collect();
+        let path = dir.write("index.txt", &contents);
+        let cache = CachedCompression::builder().progressive_flush(4096).build();
+        for algo in Algorithm::ALL {
+            cache.compression(algo, path.clone()).await;
+            let compressed = std::fs::read(artifact_path(&path, algo)).unwrap();
+            // Each sync flush ends with an empty stored block, after any header
+            let start = if algo == Algorithm::Gzip { 10 } else { 0 };
+            let end = compressed[start..]
+                .windows(4)
+                .position(|w| w == [0, 0, 0xff, 0xff])
+                .expect("flushed")
+                + start
+                + 4;
+            assert!(end < compressed.len() / 2, "{algo}");
+
+            let mut partial = vec![];
+            let truncated = &compressed[..end];
+            let _ = match algo {
+                Algorithm::Gzip => GzDecoder::new(truncated).read_to_end(&mut partial),
+                Algorithm::DeflateRaw => DeflateDecoder::new(truncated).read_to_end(&mut partial),
+            };
+            assert!(partial.len() >= 4096, "{algo}");
+            assert!(contents.as_bytes().starts_with(&partial), "{algo}");
+        }
+        let whole = std::fs::read(artifact_path(&path, Algorithm::Gzip)).unwrap();
+        assert_eq!(gunzip(&whole), contents.as_bytes());
+    }
+
+    #[get("/ranked.txt")]
+    fn ranked() -> &'static str {
+        "From a route"
+    }
+
+    #[async_test]
+    async fn composes_with_file_server_options() {
+        let dir = TestDir::new("file-server-options");
+        let page = "<p>Hello world</p>".repeat(20);
+        let docs = "<p>Docs</p>".repeat(20);
+        dir.write("index.html", &page);
+        dir.write("docs/index.html", &docs);
+        dir.write("ranked.txt", "From a file ".repeat(20));
+        let hidden = dir.write(".hidden.txt", "Secret ".repeat(20));
+        let cache = CachedCompression::new();
+        let rocket = build()
+            .mount("/", routes![ranked])
+            .mount(
+                "/",
+                FileServer::new(dir.path()).rank(5).rewrite(cache.clone()),
+            )
+            .mount(
+                "/assets",
+                FileServer::without_index(dir.path()).rewrite(cache.clone()),
+            );
+        let client = Client::untracked(rocket).await.unwrap();
+
+        for uri in [
+            "/",
+            "/docs/",
+            "/assets/index.html",
+            "/.hidden.txt",
+            "/ranked.txt",
+        ] {
+            get(&client, uri, "gzip").await;
+        }
+        sleep(Duration::from_millis(400)).await;
+
+        // Directory indexes, and other mount points, map to the same copies
+        for (uri, body) in [
+            ("/", &page),
+            ("/docs/", &docs),
+            ("/assets/index.html", &page),
+        ] {
+            let res = get(&client, uri, "gzip").await;
+            assert_eq!(encoding(&res), Some("gzip"), "{uri}");
+            assert_eq!(
+                res.headers().get_one("Content-Type"),
+                Some("text/html; charset=utf-8")
+            );
+            assert_eq!(
+                gunzip(&res.into_bytes().await.unwrap()),
+                body.as_bytes(),
+                "{uri}"
+            );
+        }
+        assert!(dir.path().join("index.html.gzip").exists());
+        assert!(dir.path().join("docs/index.html.gzip").exists());
+
+        // Without an index, or hidden, nothing is served or compressed
+        assert_eq!(
+            get(&client, "/assets/", "gzip").await.status(),
+            Status::NotFound
+        );
+        assert_eq!(
+            get(&client, "/.hidden.txt", "gzip").await.status(),
+            Status::NotFound
+        );
+        assert!(!artifact_path(&hidden, Algorithm::Gzip).exists());
+
+        // A route ranked ahead of the `FileServer` still wins
+        let res = get(&client, "/ranked.txt", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), "From a route");
+        assert!(!dir.path().join("ranked.txt.gzip").exists());
+    }
+
+    #[async_test]
+    async fn eligibility_per_subtree() {
+        let dir = TestDir::new("eligible-subtrees");
+        let assets = dir.write("assets/app.js", "let hello = 'world';".repeat(20));
+        let downloads = dir.write("downloads/notes.txt", "Hello world ".repeat(20));
+        let cache = CachedCompression::builder()
+            .eligible_when(|path| path.starts_with("/assets/"))
+            .build();
+        let rocket = build()
+            .mount(
+                "/assets",
+                FileServer::new(dir.path().join("assets")).rewrite(cache.clone()),
+            )
+            .mount(
+                "/downloads",
+                FileServer::new(dir.path().join("downloads")).rewrite(cache),
+            );
+        let client = Client::untracked(rocket).await.unwrap();
+        get(&client, "/assets/app.js", "gzip").await;
+        get(&client, "/downloads/notes.txt", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let res = get(&client, "/assets/app.js", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert!(artifact_path(&assets, Algorithm::Gzip).exists());
+
+        let res = get(&client, "/downloads/notes.txt", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.headers().get_one("Vary"), None);
+        assert!(!artifact_path(&downloads, Algorithm::Gzip).exists());
+    }
+
+    #[async_test]
+    async fn launch_checks_temp_dir() {
+        let dir = TestDir::new("launch-check");
+        dir.write("index.txt", "Hello world");
+        let missing = dir.path().join("tmp");
+        let cache = CachedCompression::builder()
+            .temp_dir(missing.clone())
+            .build();
+        let e = cache.check().await.unwrap_err();
         assert_eq!(
-            res.headers().get_one("Content-Type").unwrap(),
-            "text/plain; charset=utf-8"
+            e.to_string(),
+            format!("temp_dir() {} does not exist", missing.display())
         );
-        if compressed {
-            assert_eq!(res.headers().get_one("Content-Encoding").unwrap(), "gzip");
+        assert!(matches!(e, Error::Io(e) if e.kind() == io::ErrorKind::NotFound));
+        let rocket = launch_in(dir.path(), cache.clone()).attach(cache.clone());
+        match rocket.ignite().await {
+            Err(e) => assert!(matches!(
+                e.kind(),
+                rocket::error::ErrorKind::FailedFairings(_)
+            )),
+            Ok(_) => panic!("launched without a temp_dir()"),
+        }
+
+        std::fs::create_dir(&missing).unwrap();
+        let rocket = launch_in(dir.path(), cache.clone()).attach(cache);
+        assert!(rocket.ignite().await.is_ok());
+        assert_eq!(std::fs::read_dir(&missing).unwrap().count(), 0);
+
+        // Something that isn't a directory can't be written into either
+        let file = dir.path().join("index.txt");
+        let cache = CachedCompression::builder().temp_dir(file).build();
+        match cache.check().await {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotADirectory),
+            res => panic!("expected an I/O error, got {res:?}"),
+        }
+    }
+
+    #[async_test]
+    async fn streamed_without_content_length() {
+        let dir = TestDir::new("stream-length");
+        let contents: String = (0..200_000).map(|i| format!("{i},")).collect();
+        dir.write("big.csv", &contents);
+        let rocket = build()
+            .mount("/", routes![stream])
+            .manage(CachedCompression::new())
+            .manage(dir.path().to_path_buf());
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let res = get(&client, "/stream/big.csv", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(res.headers().get_one("Content-Length"), None);
+        let body = res.into_bytes().await.unwrap();
+        assert!(body.len() < contents.len());
+        assert_eq!(gunzip(&body), contents.as_bytes());
+    }
+
+    #[async_test]
+    async fn cache_version_bumped() {
+        let dir = TestDir::new("cache-version");
+        let path = dir.write("index.txt", "Hello world");
+        let v1 = CachedCompression::builder().cache_version("v1").build();
+        let client = Client::untracked(launch_in(dir.path(), v1)).await.unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), Some("gzip"));
+        let old = super::artifact_path(&path, Algorithm::Gzip, Some("v1"));
+        assert_eq!(old, dir.path().join("index.txt.v1.gzip"));
+        assert!(old.exists());
+
+        // Another deploy ignores the old copy
+        let v2 = CachedCompression::builder().cache_version("v2").build();
+        let client = Client::untracked(launch_in(dir.path(), v2.clone()))
+            .await
+            .unwrap();
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), None);
+        sleep(Duration::from_millis(400)).await;
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+        let new = super::artifact_path(&path, Algorithm::Gzip, Some("v2"));
+        assert!(new.exists());
+
+        assert_eq!(v2.prune_orphans(dir.path()).await.unwrap(), 1);
+        assert!(!old.exists());
+        assert!(new.exists());
+    }
+
+    #[async_test]
+    async fn preference_picks_among_cached() {
+        let dir = TestDir::new("preference-cached");
+        let path = dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder()
+            .prefer(&[Algorithm::DeflateRaw, Algorithm::Gzip])
+            .build();
+        for algo in Algorithm::ALL {
+            cache.compression(algo, path.clone()).await;
+        }
+        for accept in ["gzip, deflate-raw", "deflate-raw, gzip", "*"] {
+            let decision = cache.decide(accept, &path);
             assert_eq!(
-                res.into_bytes().await.unwrap(),
-                include_bytes!("../static/index.txt.pre-gziped")
+                decision,
+                Decision::ServeCompressed(Algorithm::DeflateRaw),
+                "{accept}"
             );
-        } else {
-            assert_eq!(res.headers().get_one("Content-Encoding"), None);
-            assert_eq!(
-                res.into_string().await.unwrap(),
-                include_str!("../static/index.txt")
+        }
+        // A higher quality still wins over the preference
+        let decision = cache.decide("gzip, deflate-raw;q=0.5", &path);
+        assert_eq!(decision, Decision::ServeCompressed(Algorithm::Gzip));
+
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        let res = get(&client, "/", "gzip, deflate-raw").await;
+        assert_eq!(encoding(&res), Some("deflate-raw"));
+        assert_eq!(inflate(&res.into_bytes().await.unwrap()), b"Hello world");
+    }
+
+    #[async_test]
+    async fn compress_file_standalone() {
+        let dir = TestDir::new("compress-file");
+        let contents: String = (0..10_000).map(|i| format!("{i} ")).collect();
+        let path = dir.write("index.txt", &contents);
+        let out = dir.path().join("out");
+        let compress = |algo, level, buffer| {
+            CachedCompression::compress_file(algo, level, buffer, &path, &out)
+        };
+        for buffer in [16, 100, 1024, 64 * 1024] {
+            let size = compress(Algorithm::Gzip, 6, buffer).await.unwrap();
+            assert_eq!(size, std::fs::metadata(&out).unwrap().len());
+            assert_eq!(gunzip(&std::fs::read(&out).unwrap()), contents.as_bytes());
+
+            compress(Algorithm::DeflateRaw, 1, buffer).await.unwrap();
+            assert_eq!(inflate(&std::fs::read(&out).unwrap()), contents.as_bytes());
+        }
+
+        // The same as the copies served, with the default options
+        let cache = CachedCompression::new();
+        cache.compression(Algorithm::Gzip, path.clone()).await;
+        let level = CachedCompression::DEFAULT_LEVEL;
+        compress(Algorithm::Gzip, level, CachedCompression::BUFFER_SIZE)
+            .await
+            .unwrap();
+        let copy = std::fs::read(artifact_path(&path, Algorithm::Gzip)).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), copy);
+
+        let e = compress(Algorithm::Gzip, 10, 1024).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let e = compress(Algorithm::Gzip, 6, 0).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[async_test]
+    async fn registered_files_compressed() {
+        let dir = TestDir::new("register");
+        let upload = dir.write("upload.txt", "Hello world");
+        let logo = dir.write("logo.png", b"\x89PNG\r\n\x1a\n");
+        let cache = CachedCompression::builder().exclude("image/*").build();
+        cache.register(&upload);
+        cache.register(&logo);
+        sleep(Duration::from_millis(400)).await;
+
+        for algo in Algorithm::ALL {
+            assert!(cache.is_cached(&upload, algo));
+            assert!(artifact_path(&upload, algo).exists());
+            assert!(!artifact_path(&logo, algo).exists());
+        }
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        let res = get(&client, "/upload.txt", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+    }
+
+    #[async_test]
+    async fn vary_only_on_compressible() {
+        let dir = TestDir::new("vary-compressible");
+        dir.write("index.html", "<p>Hello world</p>");
+        dir.write("logo.png", b"\x89PNG\r\n\x1a\n");
+        let cache = CachedCompression::builder()
+            .vary_only_if_compressible()
+            .build();
+        let rocket = build().mount("/", FileServer::new(dir.path()).rewrite(cache));
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let res = get(&client, "/index.html", "gzip").await;
+        assert_eq!(res.headers().get_one("Vary"), Some("Accept-Encoding"));
+        let res = get(&client, "/logo.png", "gzip").await;
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.headers().get_one("Vary"), None);
+    }
+
+    #[async_test]
+    async fn paths_outside_root_skipped() {
+        let dir = TestDir::new("source-root");
+        let other = TestDir::new("source-root-other");
+        let inside = dir.write("index.txt", "Hello world");
+        let outside = other.write("index.txt", "Hello world");
+        let name = other.path().file_name().unwrap();
+        let escaping = dir.path().join("..").join(name).join("index.txt");
+        let cache = CachedCompression::builder().source_root(dir.path()).build();
+        for path in [&inside, &outside, &escaping] {
+            cache.dispatch(Algorithm::Gzip, path.clone());
+        }
+        sleep(Duration::from_millis(400)).await;
+
+        assert!(artifact_path(&inside, Algorithm::Gzip).exists());
+        assert!(!artifact_path(&outside, Algorithm::Gzip).exists());
+        assert!(!cache.is_cached(&outside, Algorithm::Gzip));
+        assert!(!cache.is_cached(&escaping, Algorithm::Gzip));
+    }
+
+    #[async_test]
+    async fn identity_after_caching() {
+        let dir = TestDir::new("identity-after-cache");
+        let path = dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::new();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        get(&client, "/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        assert!(cache.is_cached(&path, Algorithm::Gzip));
+
+        for accept in ["identity", "gzip;q=0", "deflate, br"] {
+            let res = get(&client, "/", accept).await;
+            assert_eq!(encoding(&res), None, "{accept}");
+            assert_eq!(res.headers().get_one("Vary"), Some("Accept-Encoding"));
+            assert_eq!(res.into_string().await.unwrap(), "Hello world");
+        }
+        let res = client.get("/").dispatch().await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), "Hello world");
+    }
+
+    #[async_test]
+    async fn low_priority_round_trips() {
+        let dir = TestDir::new("low-priority");
+        let contents: String = (0..100_000).map(|i| format!("{i}\n")).collect();
+        let path = dir.write("index.txt", &contents);
+        let cache = CachedCompression::builder().low_priority().build();
+        for algo in Algorithm::ALL {
+            cache.compression(algo, path.clone()).await;
+            assert!(cache.is_cached(&path, algo));
+        }
+
+        let gzipped = std::fs::read(artifact_path(&path, Algorithm::Gzip)).unwrap();
+        assert_eq!(gunzip(&gzipped), contents.as_bytes());
+        let deflated = std::fs::read(artifact_path(&path, Algorithm::DeflateRaw)).unwrap();
+        assert_eq!(inflate(&deflated), contents.as_bytes());
+    }
+
+    #[async_test]
+    async fn fallback_waits_for_window() {
+        use std::time::UNIX_EPOCH;
+
+        let dir = TestDir::new("fallback-window");
+        let path = dir.write("index.txt", "Hello world");
+        // Seconds into the day, starting before the window
+        let now = Arc::new(AtomicU64::new(3600));
+        let clock = now.clone();
+        let cache = CachedCompression::builder()
+            .store(FailFor("deflate"))
+            .fallback(&[Algorithm::DeflateRaw, Algorithm::Gzip])
+            .compress_window(Duration::from_secs(2 * 3600), Duration::from_secs(4 * 3600))
+            .clock(move || UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::Relaxed)))
+            .build();
+        assert!(!cache.warm_file(&path, Algorithm::DeflateRaw).await);
+        let queued = |algo| {
+            cache
+                .deferred
+                .lock()
+                .unwrap()
+                .jobs
+                .iter()
+                .any(|j| j.0 == algo)
+        };
+        assert!(queued(Algorithm::Gzip));
+        assert!(!cache.is_cached(&path, Algorithm::Gzip));
+
+        now.store(3 * 3600, Ordering::Relaxed);
+        sleep(WINDOW_POLL + Duration::from_millis(400)).await;
+        assert!(cache.is_cached(&path, Algorithm::Gzip));
+    }
+
+    #[async_test]
+    async fn warm_file_waits() {
+        let dir = TestDir::new("warm-file");
+        let path = dir.write("index.txt", "Hello world");
+        let empty = dir.write("empty.txt", "");
+        let cache = CachedCompression::new();
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        assert!(!cache.warm_file(&empty, Algorithm::Gzip).await);
+        assert!(
+            !cache
+                .warm_file(dir.path().join("missing.txt"), Algorithm::Gzip)
+                .await
+        );
+
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+        assert_eq!(cache.counters().hits, 1);
+        assert_eq!(cache.counters().misses, 0);
+    }
+
+    /// Sets the length of the original file, like an application might.
+    struct OriginalLength;
+
+    impl Rewriter for OriginalLength {
+        fn rewrite<'h>(
+            &self,
+            path: Option<Rewrite<'h>>,
+            _req: &rocket::Request<'_>,
+        ) -> Option<Rewrite<'h>> {
+            match path {
+                Some(Rewrite::File(mut file)) => {
+                    let length = std::fs::metadata(&file.path).map_or(0, |meta| meta.len());
+                    file.headers
+                        .add(Header::new("Content-Length", length.to_string()));
+                    Some(Rewrite::File(file))
+                }
+                path => path,
+            }
+        }
+    }
+
+    #[async_test]
+    async fn stale_content_length_removed() {
+        let dir = TestDir::new("stale-length");
+        let contents = "Hello world ".repeat(100);
+        let path = dir.write("index.txt", &contents);
+        let cache = CachedCompression::new();
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        let rocket = build().mount(
+            "/",
+            FileServer::without_index(dir.path())
+                .rewrite(DirIndex::unconditional("index.txt"))
+                .rewrite(OriginalLength)
+                .rewrite(cache),
+        );
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        let length = res.headers().get_one("Content-Length").map(str::to_string);
+        let body = res.into_bytes().await.unwrap();
+        assert!(body.len() < contents.len());
+        let correct = length
+            .as_deref()
+            .is_none_or(|length| length == body.len().to_string());
+        assert!(correct, "{length:?}");
+        assert_eq!(gunzip(&body), contents.as_bytes());
+
+        let res = get(&client, "/", "identity").await;
+        let length = contents.len().to_string();
+        assert_eq!(res.headers().get_one("Content-Length"), Some(&*length));
+    }
+
+    #[async_test]
+    async fn cold_miss_retry_after() {
+        let dir = TestDir::new("cold-miss");
+        dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder()
+            .cold_miss_policy(ColdMissPolicy::RetryAfter(Duration::from_millis(1500)))
+            .build();
+        let rocket = launch_in(dir.path(), cache.clone()).attach(cache);
+        let client = Client::untracked(rocket).await.unwrap();
+
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        assert_eq!(res.headers().get_one("Retry-After"), Some("2"));
+        assert_eq!(encoding(&res), None);
+        assert!(res.into_bytes().await.unwrap().is_empty());
+
+        // Only the client waiting on a copy is turned away
+        let res = get(&client, "/", "identity").await;
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.into_string().await.unwrap(), "Hello world");
+
+        sleep(Duration::from_millis(400)).await;
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+    }
+
+    #[async_test]
+    async fn append_mode() {
+        use flate2::read::MultiGzDecoder;
+        use std::io::{Read, Write};
+
+        let decode = |bytes: &[u8]| {
+            let mut out = String::new();
+            MultiGzDecoder::new(bytes).read_to_string(&mut out).unwrap();
+            out
+        };
+        let dir = TestDir::new("append-mode");
+        let path = dir.write("app.log", &"first line\n".repeat(50));
+        let artifact = artifact_path(&path, Algorithm::Gzip);
+        let cache = CachedCompression::builder().append_mode().build();
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        let before = std::fs::read(&artifact).unwrap();
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all("second line\n".repeat(50).as_bytes())
+            .unwrap();
+        drop(file);
+        assert_eq!(
+            cache.decide("gzip", &path),
+            Decision::Dispatch(Algorithm::Gzip)
+        );
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        let after = std::fs::read(&artifact).unwrap();
+        assert!(after.len() > before.len());
+        assert!(after.starts_with(&before), "the copy was compressed again");
+        assert_eq!(decode(&after), std::fs::read_to_string(&path).unwrap());
+
+        // Truncated, so compressed again in full
+        std::fs::write(&path, "fresh start\n").unwrap();
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        let rewritten = std::fs::read(&artifact).unwrap();
+        assert_eq!(decode(&rewritten), "fresh start\n");
+        assert_eq!(gunzip(&rewritten), b"fresh start\n");
+    }
+
+    #[async_test]
+    async fn resolve_without_dispatch() {
+        let dir = TestDir::new("resolve");
+        let path = dir.write("index.txt", "Hello world");
+        let other = dir.write("other.txt", "Hello world");
+        let cache = CachedCompression::builder()
+            .eligible_when(|path| !path.starts_with("/private"))
+            .build();
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        let client = Client::untracked(build()).await.unwrap();
+        let request = |uri: &'static str| {
+            client
+                .get(uri)
+                .header(Header::new("Accept-Encoding", "gzip"))
+        };
+
+        let hit = ResolvedTarget::Compressed {
+            algo: Algorithm::Gzip,
+            artifact: artifact_path(&path, Algorithm::Gzip),
+        };
+        assert_eq!(cache.resolve(request("/index.txt").inner(), &path), hit);
+        let cold = cache.resolve(request("/other.txt").inner(), &other);
+        assert_eq!(cold, ResolvedTarget::Identity);
+        let ineligible = cache.resolve(request("/private/index.txt").inner(), &path);
+        assert_eq!(ineligible, ResolvedTarget::Identity);
+
+        // Nothing was dispatched for the cold miss, or counted
+        sleep(Duration::from_millis(200)).await;
+        assert!(!artifact_path(&other, Algorithm::Gzip).exists());
+        assert_eq!(cache.counters().hits, 0);
+        assert_eq!(cache.counters().misses, 0);
+    }
+
+    #[async_test]
+    async fn resolve_keeps_no_policy() {
+        let dir = TestDir::new("resolve-policy");
+        let path = dir.write("index.txt", "Hello world");
+        dir.write("index.txt.policy", "algorithm = \"deflate-raw\"");
+        let cache = CachedCompression::builder().file_policies().build();
+        assert!(cache.warm_file(&path, Algorithm::DeflateRaw).await);
+        let other = dir.write("other.txt", "Hello world");
+        dir.write("other.txt.policy", "algorithm = \"deflate-raw\"");
+        let client = Client::untracked(build()).await.unwrap();
+        let accept = Header::new("Accept-Encoding", "gzip, deflate-raw");
+        let request = |uri: &'static str| client.get(uri).header(accept.clone());
+
+        let hit = ResolvedTarget::Compressed {
+            algo: Algorithm::DeflateRaw,
+            artifact: artifact_path(&path, Algorithm::DeflateRaw),
+        };
+        assert_eq!(cache.resolve(request("/index.txt").inner(), &path), hit);
+        let cold = cache.resolve(request("/other.txt").inner(), &other);
+        assert_eq!(cold, ResolvedTarget::Identity);
+        // The policy was read, but not kept
+        assert!(cache.map.get(&other).is_none());
+        assert_eq!(cache.decide("gzip", &other), Decision::ServeIdentity);
+        assert!(cache.map.get(&other).is_none());
+    }
+
+    #[async_test]
+    async fn compress_under_mounts() {
+        let dir = TestDir::new("compress-under");
+        let assets = dir.write("assets/app.js", "let hello = 'world';".repeat(20));
+        let downloads = dir.write("downloads/notes.txt", "Hello world ".repeat(20));
+        let cache = CachedCompression::builder()
+            .compress_under("/assets/")
+            .build();
+        let rocket = build()
+            .mount(
+                "/assets",
+                FileServer::new(dir.path().join("assets")).rewrite(cache.clone()),
+            )
+            .mount(
+                "/downloads",
+                FileServer::new(dir.path().join("downloads")).rewrite(cache),
             );
+        let client = Client::untracked(rocket).await.unwrap();
+        get(&client, "/assets/app.js", "gzip").await;
+        get(&client, "/downloads/notes.txt", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        let res = get(&client, "/assets/app.js", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert!(artifact_path(&assets, Algorithm::Gzip).exists());
+        let res = get(&client, "/downloads/notes.txt", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert!(!artifact_path(&downloads, Algorithm::Gzip).exists());
+
+        let cache = CachedCompression::builder()
+            .compress_under("/assets")
+            .build();
+        assert!(cache.config.under_mount("/assets"));
+        assert!(!cache.config.under_mount("/assets-old/app.js"));
+    }
+
+    #[async_test]
+    async fn x_gzip_accepted() {
+        let dir = TestDir::new("x-gzip");
+        let path = dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::new();
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        assert_eq!(
+            cache.decide("x-gzip;q=0, gzip", &path),
+            Decision::ServeIdentity
+        );
+
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        let res = get(&client, "/", "x-gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+    }
+
+    #[async_test]
+    async fn in_memory_hits() {
+        use std::sync::Mutex;
+
+        let dir = TestDir::new("in-memory-hits");
+        let path = dir.write("index.txt", "Hello world");
+        let artifact = artifact_path(&path, Algorithm::Gzip);
+        let reported = Arc::new(Mutex::new(vec![]));
+        let cache = CachedCompression::builder()
+            .track_inode()
+            .in_memory_hits(Duration::from_millis(100))
+            .on_cache_error({
+                let reported = reported.clone();
+                move |path, _err| reported.lock().unwrap().push(path.to_path_buf())
+            })
+            .build();
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        let client = Client::untracked(build()).await.unwrap();
+        let req = client
+            .get("/")
+            .header(Header::new("Accept-Encoding", "gzip"));
+        let served = || {
+            let file = Some(Rewrite::File(File::new(path.as_path())));
+            match cache.rewrite(file, req.inner()) {
+                Some(Rewrite::File(file)) => file.path.into_owned(),
+                _ => panic!("not a file"),
+            }
+        };
+
+        // With both files gone the hit still succeeds, so neither was looked at
+        std::fs::remove_file(&artifact).unwrap();
+        let moved = dir.path().join("moved.txt");
+        std::fs::rename(&path, &moved).unwrap();
+        assert_eq!(served(), artifact);
+        assert!(reported.lock().unwrap().is_empty());
+
+        // Until the background check notices
+        std::fs::rename(&moved, &path).unwrap();
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(*reported.lock().unwrap(), vec![artifact.clone()]);
+        assert_eq!(served(), path);
+    }
+
+    #[async_test]
+    async fn in_memory_hits_keep_size_and_policy() {
+        let dir = TestDir::new("in-memory-policy");
+        let small = dir.write("small.txt", "Hello world");
+        let pinned = dir.write("pinned.txt", "Hello world");
+        dir.write("pinned.txt.policy", "algorithm = \"gzip\"");
+        let cache = CachedCompression::builder()
+            .size_based_algorithm(1024, Algorithm::DeflateRaw, Algorithm::Gzip)
+            .file_policies()
+            .in_memory_hits(Duration::from_secs(60))
+            .build();
+        assert!(cache.warm_file(&small, Algorithm::Gzip).await);
+        assert!(cache.warm_file(&small, Algorithm::DeflateRaw).await);
+        assert!(cache.warm_file(&pinned, Algorithm::Gzip).await);
+
+        // Neither the size nor the policy is read again for a hit
+        std::fs::write(&small, "Hello world\n".repeat(1000)).unwrap();
+        std::fs::remove_file(dir.path().join("pinned.txt.policy")).unwrap();
+        let accept = "gzip, deflate-raw";
+        let served = Decision::ServeCompressed(Algorithm::DeflateRaw);
+        assert_eq!(cache.decide(accept, &small), served);
+        let served = Decision::ServeCompressed(Algorithm::Gzip);
+        assert_eq!(cache.decide(accept, &pinned), served);
+    }
+
+    #[async_test]
+    async fn indexes_keyed_by_full_path() {
+        let dir = TestDir::new("index-keys");
+        let alpha = dir.write("alpha/index.txt", "Alpha ".repeat(20));
+        let beta = dir.write("beta/index.txt", "Beta ".repeat(20));
+        let cache = CachedCompression::new();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        get(&client, "/alpha/", "gzip").await;
+        get(&client, "/beta/", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+
+        assert!(cache.is_cached(&alpha, Algorithm::Gzip));
+        assert!(cache.is_cached(&beta, Algorithm::Gzip));
+        for (uri, contents) in [("/alpha/", "Alpha "), ("/beta/", "Beta ")] {
+            let res = get(&client, uri, "gzip").await;
+            assert_eq!(encoding(&res), Some("gzip"));
+            let body = gunzip(&res.into_bytes().await.unwrap());
+            assert_eq!(body, contents.repeat(20).as_bytes());
         }
     }
 
     #[async_test]
-    async fn simple_test() {
-        let mut client = Client::untracked(launch()).await.unwrap();
-        gzipped_req(&mut client, "gzip", false).await;
+    async fn wasm_compressed() {
+        assert_eq!(
+            content_type_from_path("static/app.wasm"),
+            Some(ContentType::WASM)
+        );
+        assert!(archive_content_type(Path::new("static/app.wasm")).is_none());
+
+        let dir = TestDir::new("wasm");
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        module.extend(b"\x01\x07\x01\x60\x02\x7f\x7f\x01\x7f".repeat(50));
+        dir.write("app.wasm", &module);
+        let client = Client::untracked(launch_in(dir.path(), CachedCompression::new()))
+            .await
+            .unwrap();
+        get(&client, "/app.wasm", "gzip").await;
         sleep(Duration::from_millis(400)).await;
 
-        gzipped_req(&mut client, "gzip", true).await;
-        gzipped_req(&mut client, "gzip; q=1", true).await;
-        gzipped_req(&mut client, "gzip; q = 0.5", true).await;
-        gzipped_req(&mut client, "gzip; q = 0", false).await;
-        gzipped_req(&mut client, "flate", false).await;
-        gzipped_req(&mut client, "flate,gzip", true).await;
-        gzipped_req(&mut client, None, false).await;
+        let res = get(&client, "/app.wasm", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(res.content_type(), Some(ContentType::WASM));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), module);
+    }
+
+    #[async_test]
+    async fn serve_only_pregenerated() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let dir = TestDir::new("serve-only");
+        let index = dir.write("index.txt", "Hello world");
+        let other = dir.write("other.txt", "Hello world");
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"Hello world").unwrap();
+        dir.write("index.txt.gzip", encoder.finish().unwrap());
+        let cache = CachedCompression::builder().serve_only().build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(gunzip(&res.into_bytes().await.unwrap()), b"Hello world");
+        let res = get(&client, "/other.txt", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        assert_eq!(res.into_string().await.unwrap(), "Hello world");
+
+        sleep(Duration::from_millis(200)).await;
+        assert!(!artifact_path(&other, Algorithm::Gzip).exists());
+        assert_eq!(cache.stats.scheduled.load(Ordering::Relaxed), 0);
+        assert_eq!(cache.counters().compressions, 0);
+        assert!(!cache.is_cached(&index, Algorithm::Gzip));
+
+        // Unlike requests, warming still compresses
+        let status = cache.warm(dir.path()).await.unwrap();
+        assert_eq!(status.queued, 2);
+        sleep(Duration::from_millis(400)).await;
+        assert!(cache.is_cached(&other, Algorithm::Gzip));
+        let status = cache.warm(dir.path()).await.unwrap();
+        assert_eq!((status.completed, status.queued), (2, 0));
+        let uploaded = dir.write("uploaded.txt", "Hello world");
+        cache.register(&uploaded);
+        sleep(Duration::from_millis(400)).await;
+        assert!(cache.is_cached(&uploaded, Algorithm::Gzip));
+        let res = get(&client, "/uploaded.txt", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+    }
+
+    /// Keeps copies under a separate directory, mirroring the sources.
+    struct Mirror(PathBuf);
+
+    impl Mirror {
+        fn at(&self, artifact: &Path) -> PathBuf {
+            self.0.join(artifact.strip_prefix("/").unwrap_or(artifact))
+        }
+    }
+
+    #[rocket::async_trait]
+    impl ArtifactStore for Mirror {
+        async fn put(&self, artifact: &Path, file: &Path) -> io::Result<()> {
+            FileSystem.put(&self.at(artifact), file).await
+        }
+
+        async fn get(&self, artifact: &Path) -> io::Result<Vec<u8>> {
+            FileSystem.get(&self.at(artifact)).await
+        }
+
+        async fn exists(&self, artifact: &Path) -> io::Result<bool> {
+            FileSystem.exists(&self.at(artifact)).await
+        }
+
+        async fn remove(&self, artifact: &Path) -> io::Result<()> {
+            FileSystem.remove(&self.at(artifact)).await
+        }
+
+        fn local_path<'a>(&self, artifact: &'a Path) -> io::Result<Cow<'a, Path>> {
+            FileSystem
+                .local_path(&self.at(artifact))
+                .map(|local| local.into_owned().into())
+        }
+    }
+
+    #[async_test]
+    async fn nested_directories_created() {
+        let dir = TestDir::new("nested-store");
+        let cache_dir = dir.path().join("cache");
+        let nested = [
+            "a/b/c/d/e/f/index.txt",
+            "a/b/c/d/e/g/index.txt",
+            "a/b/h/index.txt",
+        ];
+        let contents = "Hello world ".repeat(20);
+        let paths: Vec<_> = nested.iter().map(|p| dir.write(p, &contents)).collect();
+        let cache = CachedCompression::builder()
+            .store(Mirror(cache_dir.clone()))
+            .build();
+        // Compressed at once, so their shared parents are created concurrently
+        let tasks: Vec<_> = paths
+            .iter()
+            .map(|path| {
+                let (cache, path) = (cache.clone(), path.clone());
+                rocket::tokio::spawn(async move { cache.warm_file(&path, Algorithm::Gzip).await })
+            })
+            .collect();
+        for task in tasks {
+            assert!(task.await.unwrap());
+        }
+
+        let mirror = Mirror(cache_dir);
+        for path in &paths {
+            let copy = std::fs::read(mirror.at(&artifact_path(path, Algorithm::Gzip))).unwrap();
+            assert_eq!(gunzip(&copy), contents.as_bytes());
+            assert!(!artifact_path(path, Algorithm::Gzip).exists());
+        }
+
+        // With the default store, the temporary file next to a copy in a new
+        // directory is written once that directory is created
+        let fresh = dir.path().join("out/a/b/c/index.txt.gzip");
+        let tmp = temp_path(&fresh, None);
+        let size = CachedCompression::compress_file(Algorithm::Gzip, 6, 8192, &paths[0], &tmp);
+        assert!(size.await.is_ok());
+        FileSystem.put(&fresh, &tmp).await.unwrap();
+        assert_eq!(gunzip(&std::fs::read(&fresh).unwrap()), contents.as_bytes());
+        assert!(!tmp.exists());
+    }
+
+    #[async_test]
+    async fn warm_reports_io_error() {
+        let dir = TestDir::new("warm-error");
+        let missing = dir.path().join("missing");
+        match CachedCompression::new().warm(&missing).await {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            res => panic!("expected an I/O error, got {res:?}"),
+        }
+    }
+
+    #[async_test]
+    async fn file_policies_honored() {
+        let dir = TestDir::new("file-policy");
+        let cache = CachedCompression::builder().file_policies().build();
+        let other = dir.write("other.js", "let hello = 'world';".repeat(20));
+        dir.write("other.js.policy", "algorithm = \"deflate-raw\"\nlevel = 1");
+        let decision = cache.decide("gzip, deflate-raw", &other);
+        assert_eq!(decision, Decision::Dispatch(Algorithm::DeflateRaw));
+        assert_eq!(cache.decide("gzip", &other), Decision::ServeIdentity);
+        assert_eq!(cache.overrides(&other, true).level, Some(1));
+
+        // Read again once the file changes
+        dir.write("other.js.policy", "level = 9");
+        dir.write("other.js", "let hello = 'world';".repeat(30));
+        assert_eq!(
+            cache.overrides(&other, true),
+            Overrides {
+                algorithm: None,
+                level: Some(9)
+            }
+        );
+        assert_eq!(
+            cache.decide("gzip", &other),
+            Decision::Dispatch(Algorithm::Gzip)
+        );
+
+        // An attribute takes precedence over the policy file, on file systems
+        // supporting user attributes, which not all do, e.g. older tmpfs
+        #[cfg(target_os = "linux")]
+        {
+            use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+            let policy = br#"algorithm = "deflate-raw""#;
+            let c_path = CString::new(other.as_os_str().as_bytes()).unwrap();
+            let name = CString::new(crate::manifest::POLICY_XATTR).unwrap();
+            // So the status change time moves on, at its coarse granularity
+            sleep(Duration::from_millis(20)).await;
+            // SAFETY: both strings are NUL-terminated, and `policy` valid for its length
+            let set = unsafe {
+                let value = policy.as_ptr().cast();
+                libc::setxattr(c_path.as_ptr(), name.as_ptr(), value, policy.len(), 0)
+            };
+            if set == 0 {
+                let decision = cache.decide("gzip, deflate-raw", &other);
+                assert_eq!(decision, Decision::Dispatch(Algorithm::DeflateRaw));
+                assert_eq!(cache.overrides(&other, true).level, None);
+            }
+        }
+    }
+
+    #[async_test]
+    async fn debug_headers_show_age() {
+        let dir = TestDir::new("cache-age");
+        let path = dir.write("index.txt", "Hello world\n".repeat(100));
+        let cache = CachedCompression::builder().debug_headers().build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        let age = |res: &LocalResponse<'_>| -> u64 {
+            res.headers()
+                .get_one("X-Cache-Age")
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        assert_eq!(age(&get(&client, "/", "gzip").await), 0);
+        sleep(Duration::from_millis(1100)).await;
+        assert!(age(&get(&client, "/", "gzip").await) >= 1);
+
+        cache.invalidate_prefix(dir.path()).await;
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        assert_eq!(age(&get(&client, "/", "gzip").await), 0);
+    }
+
+    #[async_test]
+    async fn level_by_size() {
+        let dir = TestDir::new("level-by-size");
+        let small = dir.write("small.txt", "Hello world ".repeat(10));
+        let large = dir.write("large.txt", "Hello world ".repeat(1000));
+        let cache = CachedCompression::builder()
+            .level_by_size(|size| if size < 1024 { 1 } else { 9 })
+            .build();
+        assert!(cache.warm_file(&small, Algorithm::Gzip).await);
+        assert!(cache.warm_file(&large, Algorithm::Gzip).await);
+
+        let out = dir.path().join("out");
+        for (path, level) in [(&small, 1), (&large, 9)] {
+            let buffer = CachedCompression::BUFFER_SIZE;
+            CachedCompression::compress_file(Algorithm::Gzip, level, buffer, path, &out)
+                .await
+                .unwrap();
+            let copy = std::fs::read(artifact_path(path, Algorithm::Gzip)).unwrap();
+            assert_eq!(copy, std::fs::read(&out).unwrap(), "{}", path.display());
+            // The header notes the fastest and best levels
+            assert_eq!(copy[8], if level == 1 { 4 } else { 2 });
+        }
+    }
+
+    #[test]
+    fn concurrent_compressions_deduplicated() {
+        let runtime = rocket::tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let dir = TestDir::new("pending-dedup");
+            let path = dir.write("index.txt", "Hello world");
+            // Slow to finish, so every task starts while the first is pending
+            let cache = CachedCompression::builder().store(Slow).build();
+            let tasks: Vec<_> = (0..64)
+                .map(|_| cache.spawn(Algorithm::Gzip, path.clone(), Ticket::new(&cache.stats)))
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            assert_eq!(cache.counters().compressions, 1);
+            assert!(cache.is_cached(&path, Algorithm::Gzip));
+            assert!(!cache.is_pending(&path, Algorithm::Gzip));
+        });
+    }
+
+    #[async_test]
+    async fn min_requests_before_compressing() {
+        let dir = TestDir::new("min-requests");
+        let path = dir.write("index.txt", "Hello world");
+        let cache = CachedCompression::builder().min_requests(3).build();
+        let client = Client::untracked(launch_in(dir.path(), cache.clone()))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            get(&client, "/", "gzip").await;
+            sleep(Duration::from_millis(100)).await;
+            assert!(!cache.is_pending(&path, Algorithm::Gzip));
+            assert!(!cache.is_cached(&path, Algorithm::Gzip));
+        }
+
+        let res = get(&client, "/", "gzip").await;
+        assert_eq!(encoding(&res), None);
+        sleep(Duration::from_millis(400)).await;
+        assert!(cache.is_cached(&path, Algorithm::Gzip));
+        assert_eq!(encoding(&get(&client, "/", "gzip").await), Some("gzip"));
+        assert_eq!(cache.counters().misses, 3);
+    }
+
+    #[async_test]
+    async fn svg_compressed_as_text() {
+        let dir = TestDir::new("svg");
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg"><rect width="1" height="1"/></svg>"#;
+        let path = dir.write("logo.svg", svg.repeat(20));
+        dir.write("logo.png", b"\x89PNG\r\n\x1a\n");
+        let cache = CachedCompression::builder().include("text/*").build();
+        assert!(cache.config.compressible(&path));
+        assert!(!cache.config.compressible(&dir.path().join("logo.png")));
+        let no_images = CachedCompression::builder().exclude("image/*").build();
+        assert!(!no_images.config.compressible(&path));
+        let no_svg = CachedCompression::builder()
+            .exclude("image/svg+xml")
+            .build();
+        assert!(!no_svg.config.compressible(&path));
+        let no_text = CachedCompression::builder().exclude("text/*").build();
+        assert!(!no_text.config.compressible(&path));
+        assert!(no_text.config.compressible(&dir.path().join("app.wasm")));
+
+        let client = Client::untracked(launch_in(dir.path(), cache))
+            .await
+            .unwrap();
+        get(&client, "/logo.svg", "gzip").await;
+        sleep(Duration::from_millis(400)).await;
+        let res = get(&client, "/logo.svg", "gzip").await;
+        assert_eq!(encoding(&res), Some("gzip"));
+        assert_eq!(res.content_type(), Some(ContentType::SVG));
+        assert_eq!(
+            gunzip(&res.into_bytes().await.unwrap()),
+            svg.repeat(20).as_bytes()
+        );
+    }
+
+    #[async_test]
+    async fn post_process_sees_whole_copy() {
+        let dir = TestDir::new("post-process");
+        let contents = "Hello world, ".repeat(20_000);
+        let path = dir.write("hello.txt", &contents);
+        let written = Arc::new(AtomicU64::new(0));
+        let counter = written.clone();
+        let cache = CachedCompression::builder()
+            .post_process(move |chunk| {
+                counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            })
+            .build();
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+
+        let copy = std::fs::read(artifact_path(&path, Algorithm::Gzip)).unwrap();
+        assert_eq!(written.load(Ordering::Relaxed), copy.len() as u64);
+        assert_eq!(gunzip(&copy), contents.as_bytes());
+    }
+
+    /// Stores copies on disk, but reports them all missing once `forgot`.
+    struct Forgetful(Arc<AtomicBool>);
+
+    #[rocket::async_trait]
+    impl ArtifactStore for Forgetful {
+        async fn put(&self, artifact: &Path, file: &Path) -> io::Result<()> {
+            FileSystem.put(artifact, file).await
+        }
+
+        async fn get(&self, artifact: &Path) -> io::Result<Vec<u8>> {
+            FileSystem.get(artifact).await
+        }
+
+        async fn exists(&self, artifact: &Path) -> io::Result<bool> {
+            let forgot = self.0.load(Ordering::Relaxed);
+            Ok(!forgot && FileSystem.exists(artifact).await?)
+        }
+
+        async fn remove(&self, artifact: &Path) -> io::Result<()> {
+            FileSystem.remove(artifact).await
+        }
+
+        fn local_path<'a>(&self, artifact: &'a Path) -> io::Result<Cow<'a, Path>> {
+            FileSystem.local_path(artifact)
+        }
+    }
+
+    #[async_test]
+    async fn revalidate_asks_store() {
+        let dir = TestDir::new("revalidate-store");
+        let path = dir.write("index.txt", "Hello world");
+        let forgot = Arc::new(AtomicBool::new(false));
+        let cache = CachedCompression::builder()
+            .store(Forgetful(forgot.clone()))
+            .in_memory_hits(Duration::from_millis(100))
+            .build();
+        assert!(cache.warm_file(&path, Algorithm::Gzip).await);
+        sleep(Duration::from_millis(300)).await;
+        assert!(cache.is_cached(&path, Algorithm::Gzip));
+
+        // Still on disk, but gone as far as the store is concerned
+        forgot.store(true, Ordering::Relaxed);
+        sleep(Duration::from_millis(300)).await;
+        assert!(artifact_path(&path, Algorithm::Gzip).exists());
+        assert!(!cache.is_cached(&path, Algorithm::Gzip));
     }
 }
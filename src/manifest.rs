@@ -0,0 +1,248 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::SystemTime,
+};
+
+use rocket::{
+    figment::{
+        providers::{Format, Toml},
+        Figment,
+    },
+    serde::Deserialize,
+};
+
+use crate::{filter::Pattern, Algorithm};
+
+/// The name of the manifest, in the root it's read from.
+pub(crate) const MANIFEST_NAME: &str = "compression.toml";
+
+/// Per-file overrides of the algorithm and level, read from a manifest, see
+/// [`CachedCompressionBuilder::manifest`](crate::CachedCompressionBuilder::manifest).
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Manifest {
+    #[serde(default, rename = "override")]
+    overrides: Vec<Override>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Override {
+    files: String,
+    algorithm: Option<String>,
+    level: Option<u32>,
+    /// `files` parsed, and `algorithm`, once the manifest is validated.
+    #[serde(skip)]
+    parsed: Option<(Pattern, Option<Algorithm>)>,
+}
+
+impl Manifest {
+    /// Describes the first invalid override, if any, parsing each one so
+    /// looking up overrides doesn't.
+    fn validate(&mut self) -> Result<(), String> {
+        for o in &mut self.overrides {
+            let pattern = Pattern::parse(&o.files)?;
+            let algorithm = match &o.algorithm {
+                Some(name) => Some(
+                    Algorithm::from_name(name)
+                        .ok_or_else(|| format!("unknown encoding `{name}`"))?,
+                ),
+                None => None,
+            };
+            if o.level.is_some_and(|level| level > 9) {
+                return Err(format!("level for `{}` must be at most 9", o.files));
+            }
+            o.parsed = Some((pattern, algorithm));
+        }
+        Ok(())
+    }
+}
+
+/// The overrides in a policy for a single file, like an `[[override]]`
+/// without its `files`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+struct Policy {
+    algorithm: Option<String>,
+    level: Option<u32>,
+}
+
+/// The extended attribute holding the policy of a file, on Linux.
+pub(crate) const POLICY_XATTR: &str = "user.compression";
+
+/// The overrides set for the file at `path` itself, by the policy in its
+/// `user.compression` extended attribute on Linux, or otherwise in a
+/// `.policy` file next to it, e.g. `app.wasm.policy`. No policy overrides
+/// nothing, and an invalid one is described.
+pub(crate) fn file_policy(path: &Path) -> Result<Overrides, String> {
+    let shown = path.display();
+    let toml = match read_policy(path) {
+        Ok(Some(toml)) => toml,
+        Ok(None) => return Ok(Overrides::default()),
+        Err(e) => return Err(format!("failed to read the policy of {shown}: {e}")),
+    };
+    let policy: Policy = Figment::from(Toml::string(&toml))
+        .extract()
+        .map_err(|e| format!("invalid policy for {shown}: {e}"))?;
+    let algorithm = match policy.algorithm.as_deref() {
+        Some(name) => Some(
+            Algorithm::from_name(name)
+                .ok_or_else(|| format!("invalid policy for {shown}: unknown encoding `{name}`"))?,
+        ),
+        None => None,
+    };
+    if policy.level.is_some_and(|level| level > 9) {
+        return Err(format!(
+            "invalid policy for {shown}: level must be at most 9"
+        ));
+    }
+    Ok(Overrides {
+        algorithm,
+        level: policy.level,
+    })
+}
+
+/// The policy of the file at `path`, if it has one.
+fn read_policy(path: &Path) -> std::io::Result<Option<String>> {
+    if let Some(xattr) = read_xattr(path, POLICY_XATTR)? {
+        return String::from_utf8(xattr)
+            .map(Some)
+            .map_err(std::io::Error::other);
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(".policy");
+    match std::fs::read_to_string(name) {
+        Ok(toml) => Ok(Some(toml)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The value of the extended attribute `name` of the file at `path`, if set
+/// and supported by its file system.
+#[cfg(target_os = "linux")]
+fn read_xattr(path: &Path, name: &str) -> std::io::Result<Option<Vec<u8>>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    // Policies are a line or two, anything longer isn't one
+    const MAX_LEN: usize = 4096;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    let mut value = vec![0u8; MAX_LEN];
+    // SAFETY: both strings are NUL-terminated, and `value` valid for its length
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            MAX_LEN,
+        )
+    };
+    if len < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            // A missing file has no policy, its absence is left to whatever
+            // reads it to report
+            Some(libc::ENODATA | libc::ENOTSUP | libc::ENOENT) => Ok(None),
+            _ => Err(e),
+        };
+    }
+    value.truncate(len as usize);
+    Ok(Some(value))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_xattr(_path: &Path, _name: &str) -> std::io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+/// What the manifest overrides for one file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Overrides {
+    /// The only algorithm to compress the file with.
+    pub(crate) algorithm: Option<Algorithm>,
+    pub(crate) level: Option<u32>,
+}
+
+/// A manifest file, reloaded whenever its modification time changes.
+pub(crate) struct ManifestFile {
+    path: PathBuf,
+    loaded: RwLock<(Option<SystemTime>, Manifest)>,
+}
+
+impl ManifestFile {
+    /// The manifest in `root`, not loaded yet.
+    pub(crate) fn new(root: &Path) -> Self {
+        Self {
+            path: root.join(MANIFEST_NAME),
+            loaded: RwLock::default(),
+        }
+    }
+
+    /// Reload the manifest if it changed since it was last loaded, describing
+    /// why it's invalid if it is, in which case the previous one is kept. A
+    /// missing manifest overrides nothing.
+    pub(crate) fn refresh(&self) -> Result<(), String> {
+        let shown = self.path.display();
+        let mtime = match std::fs::metadata(&self.path) {
+            Ok(meta) => meta.modified().ok(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                *self.loaded.write().unwrap() = Default::default();
+                return Ok(());
+            }
+            Err(e) => return Err(format!("failed to read {shown}: {e}")),
+        };
+        if mtime.is_some() && self.loaded.read().unwrap().0 == mtime {
+            return Ok(());
+        }
+        let toml = std::fs::read_to_string(&self.path)
+            .map_err(|e| format!("failed to read {shown}: {e}"))?;
+        let mut manifest: Manifest = Figment::from(Toml::string(&toml))
+            .extract()
+            .map_err(|e| format!("invalid {shown}: {e}"))?;
+        manifest
+            .validate()
+            .map_err(|e| format!("invalid {shown}: {e}"))?;
+        *self.loaded.write().unwrap() = (mtime, manifest);
+        Ok(())
+    }
+
+    /// The overrides for the file at `path`, from the first matching entry
+    /// that sets each.
+    pub(crate) fn overrides(&self, path: &Path) -> Overrides {
+        let loaded = self.loaded.read().unwrap();
+        let mut overrides = Overrides::default();
+        for o in &loaded.1.overrides {
+            let Some((pattern, algorithm)) = &o.parsed else {
+                continue;
+            };
+            if pattern.matches(path) {
+                overrides.algorithm = overrides.algorithm.or(*algorithm);
+                overrides.level = overrides.level.or(o.level);
+            }
+        }
+        overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_validated() {
+        let parse = |toml: &str| -> Result<(), String> {
+            let mut manifest: Manifest = Figment::from(Toml::string(toml))
+                .extract()
+                .map_err(|e| e.to_string())?;
+            manifest.validate()
+        };
+        assert!(parse("").is_ok());
+        assert!(parse("[[override]]\nfiles = \"wasm\"\nalgorithm = \"gzip\"\nlevel = 6").is_ok());
+        assert!(parse("[[override]]\nfiles = \"*.wasm\"").is_err());
+        assert!(parse("[[override]]\nfiles = \"wasm\"\nalgorithm = \"br\"").is_err());
+        assert!(parse("[[override]]\nfiles = \"wasm\"\nlevel = 11").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use std::{borrow::Cow, io, path::Path};
+
+use crate::{create_parent, move_into_place};
+
+/// Where compressed copies are kept. Each copy is identified by its
+/// artifact path, the source path with the algorithm's extension appended
+/// (e.g. `static/index.txt.gzip`).
+///
+/// Compression always writes to a local temporary file first, which is handed
+/// to [`put`](Self::put) once complete. Serving goes through
+/// [`local_path`](Self::local_path), since the `FileServer` can only send local
+/// files, so a remote store needs to keep a local copy of anything it serves.
+#[rocket::async_trait]
+pub trait ArtifactStore: Send + Sync {
+    /// Store the finished copy in the local file `file` as `artifact`,
+    /// replacing any previous copy. The store may move or delete `file`; if
+    /// this fails, the caller removes it.
+    async fn put(&self, artifact: &Path, file: &Path) -> io::Result<()>;
+
+    /// The contents of the copy stored as `artifact`.
+    async fn get(&self, artifact: &Path) -> io::Result<Vec<u8>>;
+
+    /// Whether a copy is stored as `artifact`. Checked in the background, for
+    /// [`in_memory_hits`](crate::CachedCompressionBuilder::in_memory_hits),
+    /// since rewriting a request can't wait for it.
+    async fn exists(&self, artifact: &Path) -> io::Result<bool>;
+
+    /// Remove the copy stored as `artifact`. Removing a missing copy fails
+    /// with [`io::ErrorKind::NotFound`].
+    async fn remove(&self, artifact: &Path) -> io::Result<()>;
+
+    /// A local file holding the copy stored as `artifact`, for serving. Called
+    /// while rewriting a request, so this must not block for long. Returns
+    /// `artifact` itself, borrowed, if the copy is kept there, so serving it
+    /// doesn't allocate another path.
+    ///
+    /// Fails if the copy is missing or unusable, in which case it is
+    /// generated again.
+    fn local_path<'a>(&self, artifact: &'a Path) -> io::Result<Cow<'a, Path>>;
+}
+
+/// The default [`ArtifactStore`], keeping copies on the local filesystem at
+/// their artifact paths, next to the source files. Missing directories are
+/// created on [`put`](ArtifactStore::put).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileSystem;
+
+#[rocket::async_trait]
+impl ArtifactStore for FileSystem {
+    async fn put(&self, artifact: &Path, file: &Path) -> io::Result<()> {
+        // Next to the source the directory exists, but a store built on this
+        // one may keep copies elsewhere
+        create_parent(artifact).await?;
+        move_into_place(file, artifact).await
+    }
+
+    async fn get(&self, artifact: &Path) -> io::Result<Vec<u8>> {
+        rocket::tokio::fs::read(artifact).await
+    }
+
+    async fn exists(&self, artifact: &Path) -> io::Result<bool> {
+        rocket::tokio::fs::try_exists(artifact).await
+    }
+
+    async fn remove(&self, artifact: &Path) -> io::Result<()> {
+        rocket::tokio::fs::remove_file(artifact).await
+    }
+
+    fn local_path<'a>(&self, artifact: &'a Path) -> io::Result<Cow<'a, Path>> {
+        let meta = std::fs::metadata(artifact)?;
+        if !meta.is_file() {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a regular file",
+            ))
+        } else if meta.len() == 0 {
+            // Even an empty input compresses to a few bytes, so this was cut short
+            Err(io::Error::new(io::ErrorKind::InvalidData, "empty file"))
+        } else {
+            Ok(Cow::Borrowed(artifact))
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A daily window of time, in UTC, during which compression runs. Wraps
+/// around midnight if it ends before it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Window {
+    /// Offsets from midnight.
+    pub(crate) start: Duration,
+    pub(crate) end: Duration,
+}
+
+impl Window {
+    /// Describes why the window is invalid, if it is.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.start >= DAY || self.end >= DAY || self.start == self.end {
+            return Err("compress_window() needs two different times of day".into());
+        }
+        Ok(())
+    }
+
+    /// Whether the window is open at `now`.
+    pub(crate) fn contains(&self, now: SystemTime) -> bool {
+        let time = time_of_day(now);
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+
+    /// How long after `now` the window next opens, or zero if it's open.
+    pub(crate) fn until_open(&self, now: SystemTime) -> Duration {
+        if self.contains(now) {
+            return Duration::ZERO;
+        }
+        let time = time_of_day(now);
+        if time < self.start {
+            self.start - time
+        } else {
+            DAY - time + self.start
+        }
+    }
+}
+
+/// How far into its UTC day `now` is.
+fn time_of_day(now: SystemTime) -> Duration {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() % DAY.as_secs();
+    Duration::new(secs, since_epoch.subsec_nanos())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hours: u64, minutes: u64) -> SystemTime {
+        // Some days after the epoch, so the date doesn't matter
+        UNIX_EPOCH + DAY * 20_000 + Duration::from_secs(hours * 3600 + minutes * 60)
+    }
+
+    fn hours(hours: u64) -> Duration {
+        Duration::from_secs(hours * 3600)
+    }
+
+    fn window(start: u64, end: u64) -> Window {
+        Window {
+            start: hours(start),
+            end: hours(end),
+        }
+    }
+
+    #[test]
+    fn window_bounds() {
+        let night = window(1, 5);
+        assert!(night.contains(at(1, 0)));
+        assert!(night.contains(at(4, 59)));
+        assert!(!night.contains(at(5, 0)));
+        assert!(!night.contains(at(0, 59)));
+        assert_eq!(night.until_open(at(3, 0)), Duration::ZERO);
+        assert_eq!(night.until_open(at(0, 30)), Duration::from_secs(30 * 60));
+        assert_eq!(night.until_open(at(23, 0)), hours(2));
+
+        let wrapping = window(22, 2);
+        assert!(wrapping.contains(at(23, 0)));
+        assert!(wrapping.contains(at(1, 0)));
+        assert!(!wrapping.contains(at(2, 0)));
+        assert!(!wrapping.contains(at(12, 0)));
+        assert_eq!(wrapping.until_open(at(12, 0)), hours(10));
+
+        assert!(night.validate().is_ok());
+        assert!(window(3, 3).validate().is_err());
+        assert!(window(3, 24).validate().is_err());
+    }
+}